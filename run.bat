@@ -1 +1 @@
-cargo run --release -- -o "data/result.nrv" --fps 30 "data/vid/test7/*.tif"
\ No newline at end of file
+cargo run --release -- encode -o "data/result.nrv" --fps 30 "data/vid/test7/*.tif"
\ No newline at end of file
@@ -48,6 +48,29 @@ impl<'a> BitWriter<'a> {
         return Ok(());
     }
 
+    /// Writes an unsigned exp-Golomb code.
+    pub fn write_ue(&mut self, value: u32) -> Result<()> {
+        let code = value as u64 + 1;
+        let width = 64 - code.leading_zeros();
+        for _ in 1..width {
+            self.write_bit(0)?;
+        }
+        for i in (0..width).rev() {
+            self.write_bit(((code >> i) & 1) as u8)?;
+        }
+        return Ok(());
+    }
+
+    /// Writes a signed exp-Golomb code, values map to 0, 1, -1, 2, -2...
+    pub fn write_se(&mut self, value: i32) -> Result<()> {
+        let code = if value > 0 {
+            value as i64 * 2 - 1
+        } else {
+            -(value as i64) * 2
+        };
+        return self.write_ue(code as u32);
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         if self.bit_pos > 0 {
             self.writer.write_all(&self.data)?;
@@ -153,6 +176,30 @@ impl<'a> BitReader<'a> {
         }
     }
 
+    pub fn read_ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.read_bit()? == 0 {
+            zeros += 1;
+            if zeros > 32 {
+                bail!("Bad exp-Golomb code");
+            }
+        }
+        let mut code = 1u64;
+        for _ in 0..zeros {
+            code = (code << 1) | self.read_bit()? as u64;
+        }
+        return Ok((code - 1) as u32);
+    }
+
+    pub fn read_se(&mut self) -> Result<i32> {
+        let code = self.read_ue()? as i64;
+        if code & 1 == 1 {
+            return Ok(((code + 1) / 2) as i32);
+        } else {
+            return Ok((-(code / 2)) as i32);
+        }
+    }
+
     pub fn read_varint(&mut self, width: u8) -> Result<i16> {
         if width == 0 {
             return Ok(0);
@@ -2,10 +2,12 @@ use std::{
     f64::consts::PI,
     fmt,
     io::{Read, Write},
+    path::Path,
+    str::FromStr,
 };
 
-use anyhow::Result;
-use byteorder::{ReadBytesExt, LE};
+use anyhow::{bail, Context, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use once_cell::sync::Lazy;
 
 use crate::bitio::{BitReader, BitWriter};
@@ -13,11 +15,43 @@ use crate::bitio::{BitReader, BitWriter};
 #[derive(Clone)]
 pub struct Block(pub [f64; 8 * 8]);
 
+/// Quantization matrices for luma and chroma blocks, in natural (not zigzag) order.
+#[derive(Clone)]
 pub struct QMatrices {
     pub luma: [f64; 8 * 8],
     pub chroma: [f64; 8 * 8],
 }
 
+/// How a quality value turns base tables into quantization matrices.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QualityScale {
+    /// 0.0..=1.0, see [`QMatrices::with_quality`].
+    Linear,
+    /// 1..=100 as in libjpeg, see [`QMatrices::with_jpeg_quality`].
+    Jpeg,
+}
+
+impl FromStr for QualityScale {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<QualityScale> {
+        return match value.to_ascii_lowercase().as_str() {
+            "linear" => Ok(QualityScale::Linear),
+            "jpeg" => Ok(QualityScale::Jpeg),
+            _ => bail!("Unknown quality scale \"{}\", expected linear or jpeg", value),
+        };
+    }
+}
+
+impl fmt::Display for QualityScale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            QualityScale::Linear => write!(f, "linear"),
+            QualityScale::Jpeg => write!(f, "jpeg"),
+        };
+    }
+}
+
 const QMATRIX_LUMA: [f64; 8 * 8] = [
     16.0, 11.0, 10.0, 16.0, 24.0, 40.0, 51.0, 61.0, //
     12.0, 12.0, 14.0, 19.0, 26.0, 58.0, 60.0, 55.0, //
@@ -40,6 +74,30 @@ const QMATRIX_CHROMA: [f64; 8 * 8] = [
     99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0,
 ];
 
+// MPEG-2 default intra matrix, coarser towards high frequencies where the eye is less sensitive
+const QMATRIX_PERCEPTUAL: [f64; 8 * 8] = [
+    8.0, 16.0, 19.0, 22.0, 26.0, 27.0, 29.0, 34.0, //
+    16.0, 16.0, 22.0, 24.0, 27.0, 29.0, 34.0, 37.0, //
+    19.0, 22.0, 26.0, 27.0, 29.0, 34.0, 34.0, 38.0, //
+    22.0, 22.0, 26.0, 27.0, 29.0, 34.0, 37.0, 40.0, //
+    22.0, 26.0, 27.0, 29.0, 32.0, 35.0, 40.0, 48.0, //
+    26.0, 27.0, 29.0, 32.0, 35.0, 40.0, 48.0, 58.0, //
+    26.0, 27.0, 29.0, 34.0, 38.0, 46.0, 56.0, 69.0, //
+    27.0, 29.0, 35.0, 38.0, 46.0, 56.0, 69.0, 83.0,
+];
+
+// nearly flat, keeps the sharp edges of text and UI
+const QMATRIX_SCREEN: [f64; 8 * 8] = [
+    16.0, 17.0, 18.0, 19.0, 20.0, 21.0, 22.0, 23.0, //
+    17.0, 18.0, 19.0, 20.0, 21.0, 22.0, 23.0, 24.0, //
+    18.0, 19.0, 20.0, 21.0, 22.0, 23.0, 24.0, 25.0, //
+    19.0, 20.0, 21.0, 22.0, 23.0, 24.0, 25.0, 26.0, //
+    20.0, 21.0, 22.0, 23.0, 24.0, 25.0, 26.0, 27.0, //
+    21.0, 22.0, 23.0, 24.0, 25.0, 26.0, 27.0, 28.0, //
+    22.0, 23.0, 24.0, 25.0, 26.0, 27.0, 28.0, 29.0, //
+    23.0, 24.0, 25.0, 26.0, 27.0, 28.0, 29.0, 30.0,
+];
+
 const UNWRAP_PATTERN: [usize; 8 * 8] = [
     0, 1, 8, 16, 9, 2, 3, 10, //
     17, 24, 32, 25, 18, 11, 4, 5, //
@@ -1347,7 +1405,9 @@ impl Block {
         return Ok(());
     }*/
 
-    pub fn write(&self, writer: &mut BitWriter, is_luma: bool) -> Result<()> {
+    /// Writes a quantized block. The DC is coded as the difference from `dc_pred`,
+    /// which is then set to the DC of this block.
+    pub fn write(&self, writer: &mut BitWriter, is_luma: bool, dc_pred: &mut i16) -> Result<()> {
         let huffman_dc = if is_luma {
             &HUFFMAN_ENCODE_DC_LUMA
         } else {
@@ -1365,7 +1425,8 @@ impl Block {
             *d = self.0[uwi] as i16;
         }
 
-        let dc = temp[0];
+        let dc = wrap_dc(temp[0] - *dc_pred);
+        *dc_pred = temp[0];
         writer.write_vec(&huffman_dc[Block::int_width(dc) as usize])?;
         writer.write_varint(dc)?;
         let mut zeroes = 0;
@@ -1398,7 +1459,8 @@ impl Block {
         return Ok(());
     }
 
-    pub fn get_encoded_size(&self, qmatrix: &[f64], is_luma: bool) -> usize {
+    /// Size in bits [`Block::write`] would take for this block quantized with `qmatrix`.
+    pub fn get_encoded_size(&self, qmatrix: &[f64], is_luma: bool, dc_pred: &mut i16) -> usize {
         let mut tblock = self.clone();
         tblock.encode3(qmatrix);
         let mut result = 0usize;
@@ -1420,7 +1482,8 @@ impl Block {
             *d = tblock.0[uwi] as i16;
         }
 
-        let dc = temp[0];
+        let dc = wrap_dc(temp[0] - *dc_pred);
+        *dc_pred = temp[0];
         result += &huffman_dc[Block::int_width(dc)];
         result += Block::int_width(dc);
         let mut zeroes = 0;
@@ -1514,7 +1577,8 @@ impl Block {
         self.revert_dct2();
     }
 
-    pub fn read(&mut self, reader: &mut BitReader, is_luma: bool) -> Result<()> {
+    /// Reads a block written by [`Block::write`] with the same `dc_pred`.
+    pub fn read(&mut self, reader: &mut BitReader, is_luma: bool, dc_pred: &mut i16) -> Result<()> {
         let huffman_dc = if is_luma {
             &HUFFMAN_DECODE_DC_LUMA
         } else {
@@ -1531,7 +1595,8 @@ impl Block {
         let dc_width = reader.decode_huffman(huffman_dc)?;
         let dc = reader.read_varint(dc_width)?;
 
-        temp[0] = dc;
+        temp[0] = wrap_dc(dc + *dc_pred);
+        *dc_pred = temp[0];
 
         let mut i = 1usize;
 
@@ -1557,6 +1622,10 @@ impl Block {
         return Ok(());
     }
 
+    pub fn is_zero(&self) -> bool {
+        return self.0.iter().all(|d| *d == 0.0);
+    }
+
     pub fn normalize(&mut self) {
         for d in self.0.iter_mut() {
             *d = *d - 128.0;
@@ -1570,23 +1639,131 @@ impl Block {
     }
 }
 
+// DC differences wrap around, so that they always fit the 11 bit DC categories.
+// A DC is at most 8 * 255 either way, so the wrapped value is still unambiguous.
+fn wrap_dc(value: i16) -> i16 {
+    return ((value as i32 + 2047).rem_euclid(4095) - 2047) as i16;
+}
+
+/// Quantizer parameter range, see [`QMatrices::scaled`].
+pub const MIN_QP: i32 = -24;
+pub const MAX_QP: i32 = 48;
+
 impl QMatrices {
+    /// Scales the JPEG tables by `quality` in 0.0..=1.0, 1.0 gives all-ones matrices.
     pub fn new(quality: f64) -> QMatrices {
-        let quality_k = 1.0 - quality;
+        return QMatrices::preset("jpeg").unwrap().with_quality(quality);
+    }
+
+    /// Base tables: "jpeg" (the JPEG Annex K tables), "flat", "perceptual" (MPEG-2 intra, used
+    /// for both planes) or "screen" (nearly flat, for text and UI).
+    pub fn preset(name: &str) -> Option<QMatrices> {
+        let (luma, chroma) = match name {
+            "jpeg" => (QMATRIX_LUMA, QMATRIX_CHROMA),
+            "flat" => ([16.0; 8 * 8], [16.0; 8 * 8]),
+            "perceptual" => (QMATRIX_PERCEPTUAL, QMATRIX_PERCEPTUAL),
+            "screen" => (QMATRIX_SCREEN, QMATRIX_SCREEN),
+            _ => return None,
+        };
+        return Some(QMatrices { luma, chroma });
+    }
+
+    /// Reads base tables from a text file: 64 luma values, then optionally 64 chroma values
+    /// (the luma table is used for both if they are missing), in row order, separated by
+    /// whitespace or commas. Everything after a '#' on a line is a comment. Values must be in 1..=255.
+    pub fn load<P: AsRef<Path>>(filename: P) -> Result<QMatrices> {
+        let filename = filename.as_ref();
+        let text = std::fs::read_to_string(filename)
+            .with_context(|| format!("Can't read quantization matrix file {}", filename.display()))?;
+        let mut values = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            for item in line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|item| !item.is_empty())
+            {
+                let value: f64 = item
+                    .parse()
+                    .with_context(|| format!("{}:{}: bad value \"{}\"", filename.display(), line_number + 1, item))?;
+                if !(1.0..=255.0).contains(&value) {
+                    bail!(
+                        "{}:{}: value {} is out of 1..=255",
+                        filename.display(),
+                        line_number + 1,
+                        value
+                    );
+                }
+                values.push(value);
+            }
+        }
+        if values.len() != 64 && values.len() != 128 {
+            bail!(
+                "{}: expected 64 or 128 values, got {}",
+                filename.display(),
+                values.len()
+            );
+        }
+
         let mut result = QMatrices {
             luma: [0.0; 8 * 8],
             chroma: [0.0; 8 * 8],
         };
+        result.luma.copy_from_slice(&values[..64]);
+        result.chroma.copy_from_slice(&values[values.len() - 64..]);
+        return Ok(result);
+    }
+
+    /// Scales base tables by `quality` in 0.0..=1.0: 0.5 keeps them as they are, 1.0 gives all-ones matrices.
+    pub fn with_quality(&self, quality: f64) -> QMatrices {
+        let quality_k = 1.0 - quality;
+        let mut result = self.clone();
+        for item in result.luma.iter_mut().chain(result.chroma.iter_mut()) {
+            *item = 2.0 * (*item - 1.0) * quality_k + 1.0;
+        }
+        return result;
+    }
+
+    /// Scales base tables the way libjpeg does: `quality` in 1..=100, 50 keeps them as they are,
+    /// lower values scale them by 50/quality, higher ones by (100-quality)/50. Uses the same integer
+    /// arithmetic and clamps the steps to 1..=255, so the "jpeg" preset gives the same tables as libjpeg at the same quality.
+    pub fn with_jpeg_quality(&self, quality: u32) -> QMatrices {
+        let quality = quality.clamp(1, 100);
+        let scale = if quality < 50 {
+            5000 / quality
+        } else {
+            200 - quality * 2
+        };
+        let mut result = self.clone();
+        for item in result.luma.iter_mut().chain(result.chroma.iter_mut()) {
+            *item = ((item.round() as u32 * scale + 50) / 100).clamp(1, 255) as f64;
+        }
+        return result;
+    }
 
-        for (dest, src) in result.luma.iter_mut().zip(QMATRIX_LUMA.iter()) {
-            *dest = 2.0 * (src - 1.0) * quality_k + 1.0;
+    /// Writes the matrices as text that [`QMatrices::load`] reads back, luma first.
+    pub fn write_text(&self, writer: &mut dyn Write) -> Result<()> {
+        for (name, matrix) in [("luma", &self.luma), ("chroma", &self.chroma)] {
+            writeln!(writer, "# {}", name)?;
+            for row in matrix.chunks(8) {
+                let row: Vec<String> = row.iter().map(|item| format!("{:7.2}", item)).collect();
+                writeln!(writer, "{}", row.join(" "))?;
+            }
         }
-        for (dest, src) in result.chroma.iter_mut().zip(QMATRIX_CHROMA.iter()) {
-            *dest = 2.0 * (src - 1.0) * quality_k + 1.0;
+        return Ok(());
+    }
+
+    /// Matrices for quantizer parameter `qp`: every 6 steps double the step sizes, 0 keeps them as they are.
+    /// Steps never go below 1.
+    pub fn scaled(&self, qp: i32) -> QMatrices {
+        let scale = 2f64.powf(qp as f64 / 6.0);
+        let mut result = self.clone();
+        for item in result.luma.iter_mut().chain(result.chroma.iter_mut()) {
+            *item = (*item * scale).max(1.0);
         }
         return result;
     }
 
+    /// Reads the matrices as stored in the container.
     pub fn from_file(file: &mut dyn Read) -> Result<QMatrices> {
         let mut result = QMatrices {
             luma: [0.0; 8 * 8],
@@ -1601,13 +1778,127 @@ impl QMatrices {
         return Ok(result);
     }
 
+    /// Writes the matrices as stored in the container.
     pub fn write(&self, file: &mut dyn Write) -> Result<()> {
         for item in self.luma {
-            file.write_all(&item.to_ne_bytes())?;
+            file.write_f64::<LE>(item)?;
         }
         for item in self.chroma {
-            file.write_all(&item.to_ne_bytes())?;
+            file.write_f64::<LE>(item)?;
         }
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jpeg_quality_matches_libjpeg() {
+        #[rustfmt::skip]
+        let q30: [f64; 64] = [
+            27.0, 18.0, 17.0, 27.0, 40.0, 66.0, 85.0, 101.0,
+            20.0, 20.0, 23.0, 32.0, 43.0, 96.0, 100.0, 91.0,
+            23.0, 22.0, 27.0, 40.0, 66.0, 95.0, 115.0, 93.0,
+            23.0, 28.0, 37.0, 48.0, 85.0, 144.0, 133.0, 103.0,
+            30.0, 37.0, 61.0, 93.0, 113.0, 181.0, 171.0, 128.0,
+            40.0, 58.0, 91.0, 106.0, 134.0, 173.0, 188.0, 153.0,
+            81.0, 106.0, 129.0, 144.0, 171.0, 201.0, 199.0, 168.0,
+            120.0, 153.0, 158.0, 163.0, 186.0, 166.0, 171.0, 164.0,
+        ];
+        #[rustfmt::skip]
+        let q75: [f64; 64] = [
+            8.0, 6.0, 5.0, 8.0, 12.0, 20.0, 26.0, 31.0,
+            6.0, 6.0, 7.0, 10.0, 13.0, 29.0, 30.0, 28.0,
+            7.0, 7.0, 8.0, 12.0, 20.0, 29.0, 35.0, 28.0,
+            7.0, 9.0, 11.0, 15.0, 26.0, 44.0, 40.0, 31.0,
+            9.0, 11.0, 19.0, 28.0, 34.0, 55.0, 52.0, 39.0,
+            12.0, 18.0, 28.0, 32.0, 41.0, 52.0, 57.0, 46.0,
+            25.0, 32.0, 39.0, 44.0, 52.0, 61.0, 60.0, 51.0,
+            36.0, 46.0, 48.0, 49.0, 56.0, 50.0, 52.0, 50.0,
+        ];
+        let base = QMatrices::preset("jpeg").unwrap();
+        assert_eq!(base.with_jpeg_quality(30).luma, q30);
+        assert_eq!(base.with_jpeg_quality(75).luma, q75);
+        assert_eq!(base.with_jpeg_quality(50).luma, base.luma);
+    }
+
+    // writes `text` to a temporary file and loads it
+    fn load(name: &str, text: &str) -> Result<QMatrices> {
+        let filename = std::env::temp_dir().join(format!("rvc2_qmatrix_{}_{}.txt", name, std::process::id()));
+        std::fs::write(&filename, text).unwrap();
+        let result = QMatrices::load(&filename);
+        std::fs::remove_file(&filename).unwrap();
+        return result;
+    }
+
+    fn values(count: usize, start: usize) -> String {
+        return (start..start + count)
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+
+    #[test]
+    fn load_reads_one_or_two_tables() {
+        let single = load("single", &values(64, 1)).unwrap();
+        assert_eq!(single.luma[0], 1.0);
+        assert_eq!(single.luma[63], 64.0);
+        assert_eq!(single.chroma, single.luma);
+
+        let text = format!(
+            "# luma\n{}\n\n# chroma, comma separated\n{} # trailing comment\n",
+            values(64, 1),
+            values(64, 101).replace(' ', ",")
+        );
+        let both = load("both", &text).unwrap();
+        assert_eq!(both.luma, single.luma);
+        assert_eq!(both.chroma[0], 101.0);
+        assert_eq!(both.chroma[63], 164.0);
+
+        let jpeg = QMatrices::preset("jpeg").unwrap();
+        let mut text = Vec::new();
+        jpeg.write_text(&mut text).unwrap();
+        let reloaded = load("written", &String::from_utf8(text).unwrap()).unwrap();
+        assert_eq!((reloaded.luma, reloaded.chroma), (jpeg.luma, jpeg.chroma));
+    }
+
+    #[test]
+    fn load_rejects_wrong_entry_counts() {
+        for count in [1, 63, 65, 127, 129, 192] {
+            let error = load("count", &values(count, 1)).err().unwrap().to_string();
+            assert!(
+                error.ends_with(&format!("expected 64 or 128 values, got {}", count)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn load_rejects_files_without_entries() {
+        for text in ["", "\n\n", "# only a comment\n"] {
+            let error = load("empty", text).err().unwrap().to_string();
+            assert!(error.ends_with("expected 64 or 128 values, got 0"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn load_rejects_values_out_of_range() {
+        for value in ["0", "256", "-3", "0.5"] {
+            let text = format!("{}\n{} {}", values(10, 1), value, values(53, 1));
+            let error = load("range", &text).err().unwrap().to_string();
+            assert!(
+                error.ends_with(&format!(":2: value {} is out of 1..=255", value)),
+                "{}",
+                error
+            );
+        }
+        let error = load("parse", &format!("{} x12", values(63, 1)))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.ends_with(":1: bad value \"x12\""), "{}", error);
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use anyhow::{bail, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::{blocks::QMatrices, videocode::FrameType};
+
+// All multi-byte values in an .nrv file are little-endian.
+//
+// Layout:
+//   header, metadata_size + metadata entries, I matrices, P/B matrices,
+//   frames (coding order, each prefixed with its u32 size, then u8 frame type, i8 qp and u8 flags),
+//   index section, u64 offset of the index section
+
+pub const MAGIC: [u8; 4] = [b'N', b'R', b'V', b'C'];
+pub const VERSION: u8 = 11;
+
+const INDEX_MAGIC: [u8; 4] = [b'N', b'R', b'V', b'I'];
+
+const MAX_DIMENSION: u32 = 16384;
+
+const META_INT: u8 = 0;
+const META_FLOAT: u8 = 1;
+const META_TEXT: u8 = 2;
+const META_TIME: u8 = 3;
+
+/// Fixed-size header at the start of an .nrv file.
+#[derive(Clone, Debug)]
+pub struct ContainerHeader {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    pub frame_count: u32,
+}
+
+/// A typed metadata value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaValue {
+    Int(i64),
+    Float(f64),
+    Text(String),
+    /// Seconds since the Unix epoch
+    Time(u64),
+}
+
+/// Ordered key/value entries of the metadata section.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub entries: Vec<(String, MetaValue)>,
+}
+
+/// Position of a frame in the file. Entries are stored in coding order.
+#[derive(Clone, Copy, Debug)]
+pub struct IndexEntry {
+    pub offset: u64,
+    pub frame_type: FrameType,
+    pub display_index: u32,
+}
+
+/// Index section written after the last frame.
+pub struct FrameIndex {
+    pub entries: Vec<IndexEntry>,
+}
+
+/// Reads the header sections of an .nrv file and its frames in coding order.
+pub struct ContainerReader<R: Read + Seek> {
+    reader: R,
+    pub header: ContainerHeader,
+    pub i_matrices: QMatrices,
+    pub pb_matrices: QMatrices,
+    pub metadata: Metadata,
+    pub index: FrameIndex,
+    position: usize,
+}
+
+impl ContainerHeader {
+    pub fn new(width: u32, height: u32, fps: f32, frame_count: u32) -> ContainerHeader {
+        return ContainerHeader {
+            width,
+            height,
+            fps,
+            frame_count,
+        };
+    }
+
+    pub fn read(reader: &mut dyn Read) -> Result<ContainerHeader> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            bail!("Not an NRVC file (wrong magic {:?})", magic);
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            bail!("Unsupported NRVC version {} (expected {})", version, VERSION);
+        }
+
+        let header = ContainerHeader {
+            width: reader.read_u16::<LE>()? as u32,
+            height: reader.read_u16::<LE>()? as u32,
+            fps: reader.read_f32::<LE>()?,
+            frame_count: reader.read_u32::<LE>()?,
+        };
+        header.validate()?;
+        return Ok(header);
+    }
+
+    pub fn write(&self, writer: &mut dyn Write) -> Result<()> {
+        self.validate()?;
+        writer.write_all(&MAGIC)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u16::<LE>(self.width as u16)?;
+        writer.write_u16::<LE>(self.height as u16)?;
+        writer.write_f32::<LE>(self.fps)?;
+        writer.write_u32::<LE>(self.frame_count)?;
+        return Ok(());
+    }
+
+    /// Checks that the dimensions and frame rate are usable.
+    pub fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 || self.width > MAX_DIMENSION || self.height > MAX_DIMENSION {
+            bail!(
+                "Invalid frame dimensions {}x{} (must be 1..={})",
+                self.width,
+                self.height,
+                MAX_DIMENSION
+            );
+        }
+        if !self.fps.is_finite() || self.fps <= 0.0 {
+            bail!("Invalid frame rate {}", self.fps);
+        }
+        return Ok(());
+    }
+
+    pub fn mb_width(&self) -> u32 {
+        return (self.width as f64 / 16.0).ceil() as u32;
+    }
+
+    pub fn mb_height(&self) -> u32 {
+        return (self.height as f64 / 16.0).ceil() as u32;
+    }
+}
+
+impl fmt::Display for MetaValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetaValue::Int(value) => write!(f, "{}", value),
+            MetaValue::Float(value) => write!(f, "{}", value),
+            MetaValue::Text(value) => write!(f, "{}", value),
+            MetaValue::Time(value) => {
+                let days = (value / 86400) as i64;
+                let secs = value % 86400;
+                // civil date from days since 1970-01-01
+                let z = days + 719468;
+                let era = z.div_euclid(146097);
+                let doe = z - era * 146097;
+                let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+                let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+                let mp = (5 * doy + 2) / 153;
+                let day = doy - (153 * mp + 2) / 5 + 1;
+                let month = if mp < 10 { mp + 3 } else { mp - 9 };
+                let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+                write!(
+                    f,
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                    year,
+                    month,
+                    day,
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60
+                )
+            }
+        }
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        return Metadata::new();
+    }
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        return Metadata { entries: Vec::new() };
+    }
+
+    /// Sets `key`, replacing an existing entry with the same key.
+    pub fn set(&mut self, key: &str, value: MetaValue) {
+        if let Some(entry) = self.entries.iter_mut().find(|(k, _)| k == key) {
+            entry.1 = value;
+        } else {
+            self.entries.push((key.to_string(), value));
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetaValue> {
+        return self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+    }
+
+    /// Writes the metadata section, including its size prefix.
+    pub fn write(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut data = Vec::<u8>::new();
+        for (key, value) in &self.entries {
+            if key.is_empty() || key.len() > u8::MAX as usize {
+                bail!("Invalid metadata key \"{}\"", key);
+            }
+            data.write_u8(key.len() as u8)?;
+            data.write_all(key.as_bytes())?;
+            match value {
+                MetaValue::Int(value) => {
+                    data.write_u8(META_INT)?;
+                    data.write_i64::<LE>(*value)?;
+                }
+                MetaValue::Float(value) => {
+                    data.write_u8(META_FLOAT)?;
+                    data.write_f64::<LE>(*value)?;
+                }
+                MetaValue::Text(value) => {
+                    data.write_u8(META_TEXT)?;
+                    data.write_u32::<LE>(value.len() as u32)?;
+                    data.write_all(value.as_bytes())?;
+                }
+                MetaValue::Time(value) => {
+                    data.write_u8(META_TIME)?;
+                    data.write_u64::<LE>(*value)?;
+                }
+            }
+        }
+        writer.write_u32::<LE>(data.len() as u32)?;
+        writer.write_all(&data)?;
+        return Ok(());
+    }
+
+    /// Reads the metadata section, including its size prefix.
+    pub fn read(reader: &mut dyn Read) -> Result<Metadata> {
+        let size = reader.read_u32::<LE>()?;
+        let mut data = vec![0u8; size as usize];
+        reader.read_exact(&mut data)?;
+
+        let mut result = Metadata::new();
+        let mut data = &data[..];
+        while !data.is_empty() {
+            let key_len = data.read_u8()?;
+            let mut key = vec![0u8; key_len as usize];
+            data.read_exact(&mut key)?;
+            let key = String::from_utf8(key)?;
+            let value = match data.read_u8()? {
+                META_INT => MetaValue::Int(data.read_i64::<LE>()?),
+                META_FLOAT => MetaValue::Float(data.read_f64::<LE>()?),
+                META_TEXT => {
+                    let len = data.read_u32::<LE>()?;
+                    let mut text = vec![0u8; len as usize];
+                    data.read_exact(&mut text)?;
+                    MetaValue::Text(String::from_utf8(text)?)
+                }
+                META_TIME => MetaValue::Time(data.read_u64::<LE>()?),
+                tag => bail!("Unknown metadata type {} for key \"{}\"", tag, key),
+            };
+            result.entries.push((key, value));
+        }
+        return Ok(result);
+    }
+}
+
+impl Default for FrameIndex {
+    fn default() -> FrameIndex {
+        return FrameIndex::new();
+    }
+}
+
+impl FrameIndex {
+    pub fn new() -> FrameIndex {
+        return FrameIndex { entries: Vec::new() };
+    }
+
+    pub fn push(&mut self, offset: u64, frame_type: FrameType, display_index: u32) {
+        self.entries.push(IndexEntry {
+            offset,
+            frame_type,
+            display_index,
+        });
+    }
+
+    /// Writes the index section followed by the trailing offset,
+    /// `offset` being the position of the section in the file.
+    pub fn write(&self, writer: &mut dyn Write, offset: u64) -> Result<()> {
+        writer.write_all(&INDEX_MAGIC)?;
+        writer.write_u32::<LE>(self.entries.len() as u32)?;
+        for entry in &self.entries {
+            writer.write_u64::<LE>(entry.offset)?;
+            writer.write_u8(entry.frame_type as u8)?;
+            writer.write_u32::<LE>(entry.display_index)?;
+        }
+        writer.write_u64::<LE>(offset)?;
+        return Ok(());
+    }
+
+    pub fn read_from_end<R: Read + Seek>(reader: &mut R) -> Result<FrameIndex> {
+        reader.seek(SeekFrom::End(-8))?;
+        let offset = reader.read_u64::<LE>()?;
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != INDEX_MAGIC {
+            bail!("Frame index not found (wrong magic {:?})", magic);
+        }
+        let count = reader.read_u32::<LE>()?;
+        let mut result = FrameIndex::new();
+        for _ in 0..count {
+            let offset = reader.read_u64::<LE>()?;
+            let frame_type = FrameType::try_from(reader.read_u8()?)?;
+            let display_index = reader.read_u32::<LE>()?;
+            result.push(offset, frame_type, display_index);
+        }
+        return Ok(result);
+    }
+
+    /// Coding position of the last I-frame shown at or before `display_index`.
+    pub fn keyframe_before(&self, display_index: u32) -> Option<usize> {
+        return self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.frame_type == FrameType::IFrame && entry.display_index <= display_index)
+            .max_by_key(|(_, entry)| entry.display_index)
+            .map(|(position, _)| position);
+    }
+
+    pub fn keyframe_count(&self) -> usize {
+        return self
+            .entries
+            .iter()
+            .filter(|entry| entry.frame_type == FrameType::IFrame)
+            .count();
+    }
+}
+
+impl<R: Read + Seek> ContainerReader<R> {
+    /// Reads and validates everything up to the first frame, and the frame index.
+    pub fn open(mut reader: R) -> Result<ContainerReader<R>> {
+        let header = ContainerHeader::read(&mut reader)?;
+
+        let metadata = Metadata::read(&mut reader)?;
+
+        let i_matrices = QMatrices::from_file(&mut reader)?;
+        let pb_matrices = QMatrices::from_file(&mut reader)?;
+        let frames_start = reader.stream_position()?;
+
+        let index = FrameIndex::read_from_end(&mut reader)?;
+        if index.entries.len() != header.frame_count as usize {
+            bail!(
+                "Frame index has {} entries, header declares {} frames",
+                index.entries.len(),
+                header.frame_count
+            );
+        }
+        reader.seek(SeekFrom::Start(frames_start))?;
+
+        return Ok(ContainerReader {
+            reader,
+            header,
+            i_matrices,
+            pb_matrices,
+            metadata,
+            index,
+            position: 0,
+        });
+    }
+
+    /// Coding position of the frame that `read_frame` returns next.
+    pub fn position(&self) -> usize {
+        return self.position;
+    }
+
+    /// Reads the next frame in coding order into `data` (without the frame type byte).
+    pub fn read_frame(&mut self, data: &mut Vec<u8>) -> Result<Option<IndexEntry>> {
+        let Some(entry) = self.index.entries.get(self.position).copied() else {
+            return Ok(None);
+        };
+        let data_size = self.reader.read_u32::<LE>()?;
+        if data_size == 0 {
+            bail!("Frame {} is empty", self.position);
+        }
+        let frame_type = FrameType::try_from(self.reader.read_u8()?)?;
+        if frame_type != entry.frame_type {
+            bail!(
+                "Frame {} is {:?}, index says {:?}",
+                self.position,
+                frame_type,
+                entry.frame_type
+            );
+        }
+        data.resize(data_size as usize - 1, 0);
+        self.reader.read_exact(data)?;
+        self.position += 1;
+        return Ok(Some(entry));
+    }
+
+    /// Positions the reader on the nearest I-frame shown at or before `display_index`,
+    /// so that decoding from there reaches the requested frame.
+    pub fn seek_to_frame(&mut self, display_index: u32) -> Result<IndexEntry> {
+        let Some(position) = self.index.keyframe_before(display_index) else {
+            bail!("No keyframe before frame {}", display_index);
+        };
+        let entry = self.index.entries[position];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.position = position;
+        return Ok(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(header: &ContainerHeader) -> Vec<u8> {
+        let mut data = Vec::new();
+        header.write(&mut data).unwrap();
+        return data;
+    }
+
+    fn error(data: &[u8]) -> String {
+        return ContainerHeader::read(&mut &data[..]).unwrap_err().to_string();
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let data = header_bytes(&ContainerHeader::new(1920, 1080, 29.97, 250));
+        let header = ContainerHeader::read(&mut &data[..]).unwrap();
+        assert_eq!(
+            (header.width, header.height, header.fps, header.frame_count),
+            (1920, 1080, 29.97, 250)
+        );
+    }
+
+    #[test]
+    fn header_rejects_bad_magic() {
+        let mut data = header_bytes(&ContainerHeader::new(64, 48, 25.0, 1));
+        data[..4].copy_from_slice(b"RIFF");
+        assert!(error(&data).starts_with("Not an NRVC file"));
+    }
+
+    #[test]
+    fn header_rejects_other_versions() {
+        let mut data = header_bytes(&ContainerHeader::new(64, 48, 25.0, 1));
+        for version in [0, VERSION - 1, VERSION + 1] {
+            data[4] = version;
+            assert!(error(&data).starts_with("Unsupported NRVC version"));
+        }
+    }
+
+    #[test]
+    fn header_rejects_zero_and_oversized_dimensions() {
+        let data = header_bytes(&ContainerHeader::new(64, 48, 25.0, 1));
+        for (width, height) in [(0, 48), (64, 0), (0, 0)] {
+            let mut data = data.clone();
+            data[5..7].copy_from_slice(&(width as u16).to_le_bytes());
+            data[7..9].copy_from_slice(&(height as u16).to_le_bytes());
+            assert!(error(&data).starts_with("Invalid frame dimensions"));
+        }
+        assert!(ContainerHeader::new(MAX_DIMENSION + 1, 48, 25.0, 1).validate().is_err());
+        assert!(ContainerHeader::new(64, 0, 25.0, 1).write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn header_accepts_odd_dimensions() {
+        let data = header_bytes(&ContainerHeader::new(21, 17, 25.0, 1));
+        let header = ContainerHeader::read(&mut &data[..]).unwrap();
+        assert_eq!((header.width, header.height), (21, 17));
+        assert_eq!((header.mb_width(), header.mb_height()), (2, 2));
+    }
+
+    #[test]
+    fn keyframe_before_finds_the_last_shown_keyframe() {
+        use FrameType::{BFrame, IFrame, PFrame};
+        // coding order of I0 B1 B2 P3 B4 B5 I6 B7 B8 P9
+        let mut index = FrameIndex::new();
+        for (display_index, frame_type) in [
+            (0, IFrame),
+            (3, PFrame),
+            (1, BFrame),
+            (2, BFrame),
+            (6, IFrame),
+            (4, BFrame),
+            (5, BFrame),
+            (9, PFrame),
+            (7, BFrame),
+            (8, BFrame),
+        ] {
+            index.push(display_index as u64 * 100, frame_type, display_index);
+        }
+        assert_eq!(index.keyframe_before(0), Some(0));
+        assert_eq!(index.keyframe_before(3), Some(0));
+        // the second keyframe is coded before B4 and B5 but shown after them
+        assert_eq!(index.keyframe_before(5), Some(0));
+        assert_eq!(index.keyframe_before(6), Some(4));
+        assert_eq!(index.keyframe_before(9), Some(4));
+        assert_eq!(index.keyframe_before(100), Some(4));
+        assert_eq!(index.keyframe_count(), 2);
+        assert_eq!(FrameIndex::new().keyframe_before(0), None);
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let mut metadata = Metadata::default();
+        metadata.set("count", MetaValue::Int(-1234567890123));
+        metadata.set("quality", MetaValue::Float(0.9));
+        metadata.set("title", MetaValue::Text("Caf\u{e9} \u{1f3ac}".to_string()));
+        metadata.set("empty", MetaValue::Text(String::new()));
+        metadata.set("created", MetaValue::Time(1_700_000_000));
+        metadata.set("count", MetaValue::Int(i64::MIN));
+
+        let mut data = Vec::new();
+        metadata.write(&mut data).unwrap();
+        let mut reader = &data[..];
+        let read = Metadata::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(read.entries, metadata.entries);
+        assert_eq!(read.get("count"), Some(&MetaValue::Int(i64::MIN)));
+
+        let mut data = Vec::new();
+        Metadata::default().write(&mut data).unwrap();
+        assert!(Metadata::read(&mut &data[..]).unwrap().entries.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use image::ImageReader;
+
+use crate::{planes::Plane, videocode::VideoFrame};
+
+/// A sequence of frames in display order, read one at a time.
+pub trait FrameSource {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// Frame rate, if the source format stores one.
+    fn fps(&self) -> Option<f32>;
+    /// Number of frames, if known in advance.
+    fn frame_count(&self) -> Option<usize>;
+    /// Reads the next frame into `frame`, returns `false` at the end of the sequence.
+    fn read_frame(&mut self, frame: &mut VideoFrame) -> Result<bool>;
+}
+
+/// Receives decoded frames in display order.
+pub trait FrameSink {
+    fn write_frame(&mut self, index: u32, frame: &VideoFrame) -> Result<()>;
+}
+
+/// Image files, one frame per file.
+pub struct ImageSequence {
+    files: Vec<PathBuf>,
+    position: usize,
+    width: u32,
+    height: u32,
+}
+
+impl ImageSequence {
+    pub fn new(files: Vec<PathBuf>) -> Result<ImageSequence> {
+        let Some(first) = files.first() else {
+            bail!("No input files");
+        };
+        let (width, height) = ImageReader::open(first)?.into_dimensions()?;
+        return Ok(ImageSequence {
+            files,
+            position: 0,
+            width,
+            height,
+        });
+    }
+}
+
+impl FrameSource for ImageSequence {
+    fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    fn fps(&self) -> Option<f32> {
+        return None;
+    }
+
+    fn frame_count(&self) -> Option<usize> {
+        return Some(self.files.len());
+    }
+
+    fn read_frame(&mut self, frame: &mut VideoFrame) -> Result<bool> {
+        let Some(filename) = self.files.get(self.position) else {
+            return Ok(false);
+        };
+        frame.load_from_image(filename)?;
+        self.position += 1;
+        return Ok(true);
+    }
+}
+
+/// Copies `width` x `height` samples with row stride `stride` into `plane`,
+/// repeating the last column and row into the padding.
+pub fn load_plane(plane: &mut Plane, data: &[u8], width: u32, height: u32, stride: u32) {
+    for py in 0..plane.height() {
+        let sy = py.min(height - 1);
+        for px in 0..plane.width() {
+            let sx = px.min(width - 1);
+            plane.put(px, py, data[(sx + sy * stride) as usize] as f64);
+        }
+    }
+}
+
+/// Copies the top-left `width` x `height` samples of `plane` into `data`, rounded to 8 bits.
+pub fn store_plane(plane: &Plane, data: &mut Vec<u8>, width: u32, height: u32) {
+    for py in 0..height {
+        for px in 0..width {
+            data.push(plane.get(px, py).round().clamp(0.0, 255.0) as u8);
+        }
+    }
+}
+
+/// Like [`store_plane`], but repeats each plane sample over 2x2 output samples.
+pub fn store_plane_upsampled(plane: &Plane, data: &mut Vec<u8>, width: u32, height: u32) {
+    for py in 0..height {
+        for px in 0..width {
+            data.push(plane.get(px / 2, py / 2).round().clamp(0.0, 255.0) as u8);
+        }
+    }
+}
+
+/// Like [`load_plane`], but averages 2x2 source samples into each plane sample.
+pub fn load_plane_subsampled(plane: &mut Plane, data: &[u8], width: u32, height: u32, stride: u32) {
+    for py in 0..plane.height() {
+        for px in 0..plane.width() {
+            let mut sum = 0.0;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = (px * 2 + dx).min(width - 1);
+                let sy = (py * 2 + dy).min(height - 1);
+                sum += data[(sx + sy * stride) as usize] as f64;
+            }
+            plane.put(px, py, sum / 4.0);
+        }
+    }
+}
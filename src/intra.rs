@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    bitio::{BitReader, BitWriter},
+    planes::Plane,
+};
+
+/// Spatial prediction of an intra-coded macroblock from the decoded pixels above and to the left of it.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IntraMode {
+    /// Average of the neighbours, mid-grey when there are none.
+    Dc,
+    /// Every row repeats the pixel to the left of it.
+    Horizontal,
+    /// Every column repeats the pixel above it.
+    Vertical,
+    /// A plane fitted to the neighbours, as in H.264.
+    Planar,
+}
+
+pub const INTRA_MODES: [IntraMode; 4] = [
+    IntraMode::Dc,
+    IntraMode::Horizontal,
+    IntraMode::Vertical,
+    IntraMode::Planar,
+];
+
+impl TryFrom<u8> for IntraMode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(IntraMode::Dc),
+            1 => Ok(IntraMode::Horizontal),
+            2 => Ok(IntraMode::Vertical),
+            3 => Ok(IntraMode::Planar),
+            _ => bail!("Unknown intra mode {}", value),
+        }
+    }
+}
+
+impl IntraMode {
+    /// Whether the mode can predict the area at (`x`, `y`): horizontal needs the column
+    /// to the left, vertical the row above, planar both.
+    pub fn is_available(&self, x: u32, y: u32) -> bool {
+        return match self {
+            IntraMode::Dc => true,
+            IntraMode::Horizontal => x > 0,
+            IntraMode::Vertical => y > 0,
+            IntraMode::Planar => x > 0 && y > 0,
+        };
+    }
+
+    /// Writes the mode as two bits.
+    pub fn write(&self, writer: &mut BitWriter) -> Result<()> {
+        let value = *self as u8;
+        writer.write_bit(value >> 1)?;
+        writer.write_bit(value & 1)?;
+        return Ok(());
+    }
+
+    pub fn read(reader: &mut BitReader) -> Result<IntraMode> {
+        let value = reader.read_bit()? << 1 | reader.read_bit()?;
+        return IntraMode::try_from(value);
+    }
+}
+
+/// Fills `prediction` (`size` x `size`, row by row) with the prediction of the area of `plane`
+/// at (`x`, `y`) from the pixels around it. The mode must be available there.
+pub fn predict(plane: &Plane, x: u32, y: u32, size: u32, mode: IntraMode, prediction: &mut [f64]) {
+    let top = |i: u32| plane.get(x + i, y - 1);
+    let left = |i: u32| plane.get(x - 1, y + i);
+    match mode {
+        IntraMode::Dc => {
+            let mut sum = 0.0;
+            let mut count = 0;
+            if x > 0 {
+                sum += (0..size).map(left).sum::<f64>();
+                count += size;
+            }
+            if y > 0 {
+                sum += (0..size).map(top).sum::<f64>();
+                count += size;
+            }
+            let dc = if count > 0 { sum / count as f64 } else { 128.0 };
+            prediction.fill(dc);
+        }
+        IntraMode::Horizontal => {
+            for (j, row) in prediction.chunks_mut(size as usize).enumerate() {
+                row.fill(left(j as u32));
+            }
+        }
+        IntraMode::Vertical => {
+            for row in prediction.chunks_mut(size as usize) {
+                for (i, d) in row.iter_mut().enumerate() {
+                    *d = top(i as u32);
+                }
+            }
+        }
+        IntraMode::Planar => {
+            // least squares slopes along the edges, the corner pixel stands in for index -1
+            let half = size / 2;
+            let corner = plane.get(x - 1, y - 1);
+            let mut gradient_x = 0.0;
+            let mut gradient_y = 0.0;
+            let mut weight = 0.0;
+            for i in 1..=half {
+                let before = half - i;
+                let top_before = if before > 0 { top(before - 1) } else { corner };
+                let left_before = if before > 0 { left(before - 1) } else { corner };
+                gradient_x += i as f64 * (top(half - 1 + i) - top_before);
+                gradient_y += i as f64 * (left(half - 1 + i) - left_before);
+                weight += 2.0 * (i * i) as f64;
+            }
+            let slope_x = gradient_x / weight;
+            let slope_y = gradient_y / weight;
+            let center = (top(size - 1) + left(size - 1)) / 2.0;
+            let offset = half as f64 - 1.0;
+            for (j, row) in prediction.chunks_mut(size as usize).enumerate() {
+                for (i, d) in row.iter_mut().enumerate() {
+                    let value = center + slope_x * (i as f64 - offset) + slope_y * (j as f64 - offset);
+                    *d = value.clamp(0.0, 255.0);
+                }
+            }
+        }
+    }
+}
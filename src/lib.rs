@@ -0,0 +1,44 @@
+//! rvc2 — a small DCT/motion-compensation video codec and its NRVC container.
+//!
+//! Encoding: fill a [`VideoFrame`], write a [`ContainerHeader`], [`Metadata`] and two sets of
+//! [`QMatrices`], then pass frames to a [`SequenceEncoder`], which picks frame types according to
+//! its [`GopConfig`] and writes the [`FrameIndex`] when finished.
+//!
+//! Decoding: open the file with [`ContainerReader`] and wrap it in a [`Decoder`], which yields
+//! frames in display order:
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use rvc2::{ContainerReader, Decoder};
+//!
+//! let container = ContainerReader::open(File::open("video.nrv")?)?;
+//! for frame in Decoder::new(container) {
+//!     let (index, frame) = frame?;
+//!     frame.save_to_image(format!("{:04}.png", index))?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod bitio;
+pub mod blocks;
+pub mod colors;
+pub mod container;
+pub mod frameio;
+pub mod intra;
+pub mod motion;
+pub mod planes;
+pub mod ratecontrol;
+pub mod rawyuv;
+pub mod sequence;
+pub mod twopass;
+pub mod videocode;
+pub mod y4m;
+
+pub use blocks::{QMatrices, QualityScale};
+pub use container::{ContainerHeader, ContainerReader, FrameIndex, IndexEntry, MetaValue, Metadata};
+pub use motion::MotionSearch;
+pub use planes::Plane;
+pub use ratecontrol::{RateConfig, RateController, RateMode};
+pub use sequence::{FrameStats, GopConfig, SequenceEncoder};
+pub use twopass::{PassFrame, PassStats};
+pub use videocode::{Decoder, Encoder, FrameDecoder, FrameType, VideoFrame};
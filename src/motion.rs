@@ -1,37 +1,108 @@
-use std::cmp::{max, min};
-use std::io::{Read, Write};
+use std::fmt::Display;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use crate::{
+    bitio::{BitReader, BitWriter},
     blocks::QMatrices,
     planes::Plane,
-    videocode::{MacroBlock, VideoFrame},
+    videocode::{DcPredictor, MacroBlock, VideoFrame},
 };
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum BlockType {
     New,
+    /// Vector in quarter pixels.
     Motion(i32, i32),
     Repeat(u32),
 }
 
+/// How [`MotionMap::calculate`] looks for the best integer vector before refining it to quarter pixels.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MotionSearch {
+    /// Every position within the search range.
+    Full,
+    /// Large diamond steps until the centre is the best point, then one small diamond.
+    Diamond,
+    /// Hexagon steps until the centre is the best point, then its eight neighbours.
+    Hexagon,
+    /// The eight neighbours at half the range, then at half the distance around the best of them, down to one pixel.
+    ThreeStep,
+    /// Full search on quarter-size copies of the frames, refined on the half- and full-size ones.
+    Hierarchical,
+}
+
+impl FromStr for MotionSearch {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<MotionSearch> {
+        return match value.to_ascii_lowercase().as_str() {
+            "full" => Ok(MotionSearch::Full),
+            "dia" | "diamond" => Ok(MotionSearch::Diamond),
+            "hex" | "hexagon" => Ok(MotionSearch::Hexagon),
+            "tss" | "three-step" => Ok(MotionSearch::ThreeStep),
+            "hier" | "hierarchical" => Ok(MotionSearch::Hierarchical),
+            _ => bail!(
+                "Unknown motion search \"{}\", expected full, diamond, hexagon, three-step or hierarchical",
+                value
+            ),
+        };
+    }
+}
+
+impl Display for MotionSearch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            MotionSearch::Full => write!(f, "full"),
+            MotionSearch::Diamond => write!(f, "diamond"),
+            MotionSearch::Hexagon => write!(f, "hexagon"),
+            MotionSearch::ThreeStep => write!(f, "three-step"),
+            MotionSearch::Hierarchical => write!(f, "hierarchical"),
+        };
+    }
+}
+
 pub struct MotionMap {
     pub vectors: Vec<BlockType>,
     pub width: u32,
     pub height: u32,
+    search_range: u32,
+    search: MotionSearch,
+    // macroblocks left in the current repeat run while writing or reading
+    run_left: u32,
 }
 
 const ZMP_TRESHOLD: f64 = 512.0;
 const NEW_TRESHOLD: f64 = 4096.0;
+// the fast searches stop as soon as the SAD gets this low, one level per pixel
+const EARLY_EXIT_TRESHOLD: f64 = 256.0;
+// how far, in quarter pixels, a predicted block can reach outside the reference frame:
+// integer vectors stay inside, the sub-pixel refinement moves them by up to 3/4 pixel
+const MAX_OVERHANG: i32 = 3;
+
+const LARGE_DIAMOND: [(i32, i32); 8] = [(0, -2), (1, -1), (2, 0), (1, 1), (0, 2), (-1, 1), (-2, 0), (-1, -1)];
+const SMALL_DIAMOND: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+const HEXAGON: [(i32, i32); 6] = [(-2, 0), (-1, -2), (1, -2), (2, 0), (1, 2), (-1, 2)];
+const SQUARE: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+/// Largest search range, in pixels.
+pub const MAX_SEARCH_RANGE: u32 = 64;
+pub const DEFAULT_SEARCH_RANGE: u32 = 16;
 
-fn block_diff(a: &Plane, ax: u32, ay: u32, b: &Plane, bx: u32, by: u32) -> f64 {
+// whether the integer part of a vector (in quarter pixels) is within `range` pixels
+fn is_valid_vector(vx: i32, vy: i32, range: u32) -> bool {
+    let range = -(range as i32)..=range as i32;
+    return range.contains(&vx.div_euclid(4)) && range.contains(&vy.div_euclid(4));
+}
+
+fn block_diff(a: &Plane, ax: u32, ay: u32, b: &Plane, bx: u32, by: u32, size: u32) -> f64 {
     let mut accum = 0f64;
-    for y in 0..16 {
+    for y in 0..size {
         let astart = (ax + (ay + y) * a.width()) as usize;
         let bstart = (bx + (by + y) * b.width()) as usize;
-        let aline = &a.data[astart..astart + 16];
-        let bline = &b.data[bstart..bstart + 16];
+        let aline = &a.data[astart..astart + size as usize];
+        let bline = &b.data[bstart..bstart + size as usize];
         accum += aline
             .iter()
             .zip(bline.iter())
@@ -63,6 +134,213 @@ pub fn block_stat(a: &Plane, ax: u32, ay: u32, b: &Plane, bx: u32, by: u32) -> (
     return (accum, accum_sq);
 }
 
+// SAD between the 16x16 luma block at (`ax`, `ay`) of `a` and the one at (`bx`, `by`) in quarter pixels
+// of the plane `b_half` was upsampled from
+fn block_diff_subpel(a: &Plane, ax: u32, ay: u32, b_half: &Plane, bx: i32, by: i32) -> f64 {
+    let mut accum = 0f64;
+    for y in 0..16 {
+        let astart = (ax + (ay + y) * a.width()) as usize;
+        let aline = &a.data[astart..astart + 16];
+        accum += aline
+            .iter()
+            .enumerate()
+            .map(|(x, a)| (*a - b_half.sample_quarter_upsampled(bx + x as i32 * 4, by + y as i32 * 4)).abs())
+            .sum::<f64>();
+    }
+    return accum;
+}
+
+// Refines an integer vector (in quarter pixels) to half and then quarter pixels,
+// returns the best vector and its SAD.
+fn refine_subpel(
+    cur: &Plane,
+    x: u32,
+    y: u32,
+    prev_half: &Plane,
+    (vector, sad): ((i32, i32), f64),
+    range: u32,
+) -> ((i32, i32), f64) {
+    let mut best = (vector, sad);
+    for step in [2, 1] {
+        let (center_x, center_y) = best.0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let vx = center_x + dx * step;
+                let vy = center_y + dy * step;
+                if (dx == 0 && dy == 0) || !is_valid_vector(vx, vy, range) {
+                    continue;
+                }
+                let new_d = block_diff_subpel(cur, x, y, prev_half, x as i32 * 4 + vx, y as i32 * 4 + vy);
+                if new_d < best.1 {
+                    best = ((vx, vy), new_d);
+                }
+            }
+        }
+    }
+    return best;
+}
+
+// SAD of the `size` x `size` block of `cur` at (`x`, `y`) against `prev` moved by an integer vector,
+// None when the vector leaves the search range or the plane
+fn sad_at<'a>(
+    cur: &'a Plane,
+    prev: &'a Plane,
+    x: u32,
+    y: u32,
+    size: u32,
+    range: u32,
+) -> impl Fn(i32, i32) -> Option<f64> + 'a {
+    let range = range as i32;
+    return move |vx, vy| {
+        let bx = x as i32 + vx;
+        let by = y as i32 + vy;
+        if vx.abs() > range
+            || vy.abs() > range
+            || bx < 0
+            || by < 0
+            || bx as u32 + size > prev.width()
+            || by as u32 + size > prev.height()
+        {
+            return None;
+        }
+        return Some(block_diff(cur, x, y, prev, bx as u32, by as u32, size));
+    };
+}
+
+// checks `points` (scaled by `step`) around `center`, returns the best of them and `best`
+fn check_points<T: PartialOrd + Copy>(
+    cost: &dyn Fn(i32, i32) -> Option<T>,
+    center: (i32, i32),
+    points: &[(i32, i32)],
+    step: i32,
+    best: ((i32, i32), T),
+) -> ((i32, i32), T) {
+    let mut best = best;
+    for (dx, dy) in points {
+        let vector = (center.0 + dx * step, center.1 + dy * step);
+        if let Some(new_d) = cost(vector.0, vector.1) {
+            if new_d < best.1 {
+                best = (vector, new_d);
+            }
+        }
+    }
+    return best;
+}
+
+fn search_full<T: PartialOrd + Copy>(
+    cost: &dyn Fn(i32, i32) -> Option<T>,
+    range: u32,
+    start: ((i32, i32), T),
+) -> ((i32, i32), T) {
+    let range = range as i32;
+    let mut best = start;
+    for vy in -range..=range {
+        for vx in -range..=range {
+            if let Some(new_d) = cost(vx, vy) {
+                if new_d < best.1 {
+                    best = ((vx, vy), new_d);
+                }
+            }
+        }
+    }
+    return best;
+}
+
+// steps to the best point of `pattern` until the centre stays best, then checks `refine` around it
+fn search_pattern<T: PartialOrd + Copy>(
+    cost: &dyn Fn(i32, i32) -> Option<T>,
+    pattern: &[(i32, i32)],
+    refine: &[(i32, i32)],
+    good_enough: T,
+    start: ((i32, i32), T),
+) -> ((i32, i32), T) {
+    let mut best = start;
+    loop {
+        if best.1 <= good_enough {
+            return best;
+        }
+        let center = best.0;
+        best = check_points(cost, center, pattern, 1, best);
+        if best.0 == center {
+            break;
+        }
+    }
+    return check_points(cost, best.0, refine, 1, best);
+}
+
+fn search_three_step<T: PartialOrd + Copy>(
+    cost: &dyn Fn(i32, i32) -> Option<T>,
+    range: u32,
+    good_enough: T,
+    start: ((i32, i32), T),
+) -> ((i32, i32), T) {
+    let mut best = start;
+    let mut step = (range as i32 + 1) / 2;
+    while step > 0 && best.1 > good_enough {
+        best = check_points(cost, best.0, &SQUARE, step, best);
+        step /= 2;
+    }
+    return best;
+}
+
+// `pyramid` holds the half- and quarter-size (current, previous) luma planes. The vector found by
+// a full search on the smallest level is doubled and checked with its neighbours on each larger one.
+fn search_hierarchical<T: PartialOrd + Copy>(
+    cost: &dyn Fn(i32, i32) -> Option<T>,
+    pyramid: &[(Plane, Plane)],
+    x: u32,
+    y: u32,
+    range: u32,
+    start: ((i32, i32), T),
+) -> ((i32, i32), T) {
+    let mut vector = (0, 0);
+    for (level, (cur, prev)) in pyramid.iter().enumerate().rev() {
+        let shift = level as u32 + 1;
+        let level_range = (range + (1 << shift) - 1) >> shift;
+        let level_cost = sad_at(cur, prev, x >> shift, y >> shift, 16 >> shift, level_range);
+        let center = (vector.0 * 2, vector.1 * 2);
+        let level_start = (center, level_cost(center.0, center.1).unwrap_or(f64::INFINITY));
+        vector = if level + 1 == pyramid.len() {
+            search_full(
+                &level_cost,
+                level_range,
+                ((0, 0), level_cost(0, 0).unwrap_or(f64::INFINITY)),
+            )
+            .0
+        } else {
+            check_points(&level_cost, center, &SQUARE, 1, level_start).0
+        };
+    }
+    let center = (vector.0 * 2, vector.1 * 2);
+    let best = match cost(center.0, center.1) {
+        Some(new_d) if new_d < start.1 => (center, new_d),
+        _ => start,
+    };
+    return check_points(cost, center, &SQUARE, 1, best);
+}
+
+/// Share of the macroblocks of a frame without a match in another one, judged on the quarter-size
+/// luma planes `cur` and `prev` (see [`Plane::downscale`]): a macroblock, 4x4 there, has no match
+/// when its SAD stays above the new-block threshold for every integer offset within `range / 4`
+/// pixels. Cheap enough to run on every frame before its type is decided, unlike a full
+/// [`MotionMap::calculate`].
+pub fn unmatched_ratio(cur: &Plane, prev: &Plane, range: u32) -> f64 {
+    let range = range.div_ceil(4);
+    let treshold = NEW_TRESHOLD / 16.0;
+    let (width, height) = (cur.width() / 4, cur.height() / 4);
+    let mut unmatched = 0;
+    for my in 0..height {
+        for mx in 0..width {
+            let cost = sad_at(cur, prev, mx * 4, my * 4, 4, range);
+            let start = ((0, 0), cost(0, 0).unwrap_or(f64::INFINITY));
+            if start.1 > treshold && search_full(&cost, range, start).1 > treshold {
+                unmatched += 1;
+            }
+        }
+    }
+    return unmatched as f64 / (width * height).max(1) as f64;
+}
+
 fn block_diff_ult(a: &VideoFrame, ax: u32, ay: u32, b: &VideoFrame, bx: u32, by: u32, qmatrices: &QMatrices) -> usize {
     let mut block_a = MacroBlock::new();
     let mut block_b = MacroBlock::new();
@@ -70,7 +348,7 @@ fn block_diff_ult(a: &VideoFrame, ax: u32, ay: u32, b: &VideoFrame, bx: u32, by:
     a.extract_macroblock(ax, ay, &mut block_a);
     b.extract_macroblock(bx, by, &mut block_b);
     block_a.difference(&block_b);
-    return block_a.get_encoded_size(qmatrices);
+    return block_a.get_encoded_size(qmatrices, &mut DcPredictor::disabled());
 }
 
 impl MotionMap {
@@ -81,48 +359,109 @@ impl MotionMap {
             vectors: vec![BlockType::New; (width * height) as usize],
             width,
             height,
+            search_range: DEFAULT_SEARCH_RANGE,
+            search: MotionSearch::Hexagon,
+            run_left: 0,
         };
     }
 
-    pub fn calculate(&mut self, cur_frame: &VideoFrame, prev_frame: &VideoFrame) {
+    /// Sets how far, in pixels, [`MotionMap::calculate`] looks for a match, up to [`MAX_SEARCH_RANGE`].
+    pub fn set_search_range(&mut self, range: u32) {
+        self.search_range = range.clamp(1, MAX_SEARCH_RANGE);
+    }
+
+    /// Sets the integer search [`MotionMap::calculate`] uses, hexagon by default.
+    pub fn set_search(&mut self, search: MotionSearch) {
+        self.search = search;
+    }
+
+    // half- and quarter-size luma planes for the hierarchical search, empty for the others
+    fn pyramid(&self, cur_frame: &VideoFrame, prev_frame: &VideoFrame) -> Vec<(Plane, Plane)> {
+        let mut pyramid = Vec::new();
+        if self.search == MotionSearch::Hierarchical {
+            let half = (cur_frame.y_plane.downscale(), prev_frame.y_plane.downscale());
+            let quarter = (half.0.downscale(), half.1.downscale());
+            pyramid.push(half);
+            pyramid.push(quarter);
+        }
+        return pyramid;
+    }
+
+    // best integer vector for the macroblock at (`x`, `y`), starting from `start`;
+    // `good_enough` ends the fast searches early
+    fn search_integer<T: PartialOrd + Copy>(
+        &self,
+        cost: &dyn Fn(i32, i32) -> Option<T>,
+        pyramid: &[(Plane, Plane)],
+        x: u32,
+        y: u32,
+        good_enough: T,
+        start: ((i32, i32), T),
+    ) -> ((i32, i32), T) {
+        return match self.search {
+            MotionSearch::Full => search_full(cost, self.search_range, start),
+            MotionSearch::Diamond => search_pattern(cost, &LARGE_DIAMOND, &SMALL_DIAMOND, good_enough, start),
+            MotionSearch::Hexagon => search_pattern(cost, &HEXAGON, &SQUARE, good_enough, start),
+            MotionSearch::ThreeStep => search_three_step(cost, self.search_range, good_enough, start),
+            MotionSearch::Hierarchical => {
+                if start.1 <= good_enough {
+                    return start;
+                }
+                search_hierarchical(cost, pyramid, x, y, self.search_range, start)
+            }
+        };
+    }
+
+    /// Finds a vector for every macroblock of `cur_frame` by luma SAD, returns the sum of the best SADs.
+    pub fn calculate(&mut self, cur_frame: &VideoFrame, prev_frame: &VideoFrame) -> f64 {
+        let prev_half = prev_frame.y_plane.half_pel();
+        let pyramid = self.pyramid(cur_frame, prev_frame);
+        let mut total = 0f64;
         for my in 0..self.height {
             for mx in 0..self.width {
                 let mv_index = (mx + my * self.width) as usize;
                 let dst_x = mx * 16;
                 let dst_y = my * 16;
 
-                let mut vect = (0i32, 0i32);
-                let mut min_d = block_diff(&cur_frame.y_plane, dst_x, dst_y, &prev_frame.y_plane, dst_x, dst_y);
+                let mut min_d = block_diff(&cur_frame.y_plane, dst_x, dst_y, &prev_frame.y_plane, dst_x, dst_y, 16);
                 if min_d > ZMP_TRESHOLD {
-                    for by in max(dst_y as i32 - 7, 0)..=min(dst_y as i32 + 7, prev_frame.height as i32 - 1 - 16) {
-                        for bx in max(dst_x as i32 - 7, 0)..=min(dst_x as i32 + 7, prev_frame.width as i32 - 1 - 16) {
-                            let new_d = block_diff(
-                                &cur_frame.y_plane,
-                                dst_x,
-                                dst_y,
-                                &prev_frame.y_plane,
-                                bx as u32,
-                                by as u32,
-                            );
-                            if new_d < min_d {
-                                min_d = new_d;
-                                vect = (bx - dst_x as i32, by - dst_y as i32);
-                            }
-                        }
-                    }
+                    let cost = sad_at(
+                        &cur_frame.y_plane,
+                        &prev_frame.y_plane,
+                        dst_x,
+                        dst_y,
+                        16,
+                        self.search_range,
+                    );
+                    let vect: (i32, i32);
+                    (vect, min_d) =
+                        self.search_integer(&cost, &pyramid, dst_x, dst_y, EARLY_EXIT_TRESHOLD, ((0, 0), min_d));
                     if min_d > NEW_TRESHOLD {
                         self.vectors[mv_index] = BlockType::New;
                     } else {
-                        self.vectors[mv_index] = BlockType::Motion(vect.0, vect.1);
+                        let (vector, sad) = refine_subpel(
+                            &cur_frame.y_plane,
+                            dst_x,
+                            dst_y,
+                            &prev_half,
+                            ((vect.0 * 4, vect.1 * 4), min_d),
+                            self.search_range,
+                        );
+                        min_d = sad;
+                        self.vectors[mv_index] = BlockType::Motion(vector.0, vector.1);
                     }
                 } else {
                     self.vectors[mv_index] = BlockType::Motion(0, 0);
                 }
+                total += min_d;
             }
         }
+        return total;
     }
 
     pub fn calculate_ult(&mut self, cur_frame: &VideoFrame, prev_frame: &VideoFrame, qmatrices: &QMatrices) {
+        let pyramid = self.pyramid(cur_frame, prev_frame);
+        let range = self.search_range as i32;
         let mut total = 0usize;
         for my in 0..self.height {
             for mx in 0..self.width {
@@ -133,15 +472,35 @@ impl MotionMap {
                 let mut vect = BlockType::New;
                 let mut temp = MacroBlock::new();
                 cur_frame.extract_macroblock(dst_x, dst_y, &mut temp);
-                let mut min_d = temp.get_encoded_size(qmatrices);
-                for by in max(dst_y as i32 - 7, 0)..=min(dst_y as i32 + 7, prev_frame.height as i32 - 1 - 16) {
-                    for bx in max(dst_x as i32 - 7, 0)..=min(dst_x as i32 + 7, prev_frame.width as i32 - 1 - 16) {
-                        let new_d =
-                            block_diff_ult(&cur_frame, dst_x, dst_y, &prev_frame, bx as u32, by as u32, qmatrices);
-                        if new_d < min_d {
-                            min_d = new_d;
-                            vect = BlockType::Motion(bx - dst_x as i32, by - dst_y as i32);
-                        }
+                temp.normalize();
+                let mut min_d = temp.get_encoded_size(qmatrices, &mut DcPredictor::new());
+                let cost = |vx: i32, vy: i32| {
+                    let bx = dst_x as i32 + vx;
+                    let by = dst_y as i32 + vy;
+                    if vx.abs() > range
+                        || vy.abs() > range
+                        || bx < 0
+                        || by < 0
+                        || bx as u32 + 16 > prev_frame.width
+                        || by as u32 + 16 > prev_frame.height
+                    {
+                        return None;
+                    }
+                    return Some(block_diff_ult(
+                        &cur_frame,
+                        dst_x,
+                        dst_y,
+                        &prev_frame,
+                        bx as u32,
+                        by as u32,
+                        qmatrices,
+                    ));
+                };
+                if let Some(zero_d) = cost(0, 0) {
+                    let (vector, new_d) = self.search_integer(&cost, &pyramid, dst_x, dst_y, 0, ((0, 0), zero_d));
+                    if new_d < min_d {
+                        min_d = new_d;
+                        vect = BlockType::Motion(vector.0 * 4, vector.1 * 4);
                     }
                 }
                 total += min_d;
@@ -151,99 +510,298 @@ impl MotionMap {
         print!("total: {}", total);
     }
 
-    pub fn write(&self, writer: &mut dyn Write) -> Result<()> {
-        let mut last = BlockType::Repeat(0);
-        let mut repeats = 0u32;
-        let mut data = [0u8; 1];
-        for vector in &self.vectors {
-            if *vector == last && repeats < 31 {
-                repeats += 1;
-            } else {
-                if repeats == 1 {
-                    data[0] = last.into();
-                    writer.write_all(&data)?;
-                    //println!("{:?}", last);
-                } else if repeats > 1 {
-                    data[0] = BlockType::Repeat(repeats).into();
-                    writer.write_all(&data)?;
-                    //println!("{:?}", BlockType::Repeat(repeats));
-                }
-                repeats = 0;
-                last = *vector;
-                data[0] = (*vector).into();
-                writer.write_all(&data)?;
-                //println!("{:?}", *vector);
-            }
+    /// Predicted vector of the macroblock at `index`: the median of its left, top and top-right
+    /// neighbours (top-left at the right edge), or the left one in the first row. New blocks and
+    /// missing neighbours count as zero. Only earlier macroblocks are used, so the decoder can follow.
+    pub fn predict_vector(&self, index: usize) -> (i32, i32) {
+        let width = self.width as usize;
+        let (mx, my) = (index % width, index / width);
+        let vector = |index: usize| match self.vectors[index] {
+            BlockType::Motion(x, y) => (x, y),
+            _ => (0, 0),
+        };
+        let left = if mx > 0 { vector(index - 1) } else { (0, 0) };
+        if my == 0 {
+            return left;
         }
-        if repeats == 1 {
-            data[0] = last.into();
-            writer.write_all(&data)?;
-            //println!("{:?}", last);
-        } else if repeats > 1 {
-            data[0] = BlockType::Repeat(repeats).into();
-            writer.write_all(&data)?;
-            //println!("{:?}", BlockType::Repeat(repeats));
+        let top = vector(index - width);
+        let top_right = if mx + 1 < width {
+            vector(index - width + 1)
+        } else if mx > 0 {
+            vector(index - width - 1)
+        } else {
+            (0, 0)
+        };
+        let median = |a: i32, b: i32, c: i32| a.max(b).min(a.min(b).max(c));
+        return (median(left.0, top.0, top_right.0), median(left.1, top.1, top_right.1));
+    }
+
+    /// Writes the entry of the macroblock at `index` with exp-Golomb codes, nothing if a repeat run
+    /// written earlier covers it. Macroblocks must be written in order, starting from 0.
+    /// An entry starts with ue(kind): 0 is a motion vector followed by se(x) and se(y), its difference
+    /// from [`MotionMap::predict_vector`] in quarter pixels, 1 a new block, 2 a repeat of the previous
+    /// macroblock's entry for this and the following macroblocks, followed by ue(count - 2).
+    pub fn write_vector(&mut self, writer: &mut BitWriter, index: usize) -> Result<()> {
+        if index == 0 {
+            self.run_left = 0;
+        }
+        if self.run_left > 0 {
+            self.run_left -= 1;
+            return Ok(());
+        }
+        let vector = self.vectors[index];
+        let repeats = if index > 0 && self.vectors[index - 1] == vector {
+            self.vectors[index..].iter().take_while(|next| **next == vector).count()
+        } else {
+            0
+        };
+        if repeats > 1 {
+            write_entry(writer, BlockType::Repeat(repeats as u32), (0, 0))?;
+            self.run_left = repeats as u32 - 1;
+        } else {
+            write_entry(writer, vector, self.predict_vector(index))?;
         }
         return Ok(());
     }
 
-    pub fn read(&mut self, reader: &mut dyn Read) -> Result<()> {
-        let mut index = 0usize;
-        let mut last = BlockType::Repeat(0);
-        let mut data = [0u8; 1];
-        while index < self.vectors.len() {
-            reader.read_exact(&mut data)?;
-            let cur: BlockType = data[0].into();
-            if let BlockType::Repeat(repeats) = cur {
-                //println!("{} {:?}", index, cur);
-                for _ in 0..repeats {
-                    self.vectors[index] = last;
-                    index += 1;
-                }
-            } else {
-                last = cur;
-                self.vectors[index] = cur;
-                //println!("{} {:?}", index, cur);
-                index += 1;
+    // whether the macroblock at `index` moved by (`vx`, `vy`) quarter pixels stays within
+    // MAX_OVERHANG of the reference frame
+    fn is_inside_reference(&self, index: usize, vx: i32, vy: i32) -> bool {
+        let x = (index as u32 % self.width) as i32 * 64 + vx;
+        let y = (index as u32 / self.width) as i32 * 64 + vy;
+        return x >= -MAX_OVERHANG
+            && y >= -MAX_OVERHANG
+            && x + 64 <= self.width as i32 * 64 + MAX_OVERHANG
+            && y + 64 <= self.height as i32 * 64 + MAX_OVERHANG;
+    }
+
+    /// Reads the entry of the macroblock at `index` written by [`MotionMap::write_vector`]
+    /// into the map and returns it. Fails on vectors that point further outside the reference
+    /// frame than the encoder goes.
+    pub fn read_vector(&mut self, reader: &mut BitReader, index: usize) -> Result<BlockType> {
+        if index == 0 {
+            self.run_left = 0;
+        }
+        if self.run_left > 0 {
+            self.run_left -= 1;
+            self.vectors[index] = self.vectors[index - 1];
+        } else {
+            self.read_entry_at(reader, index)?;
+        }
+        if let BlockType::Motion(vx, vy) = self.vectors[index] {
+            if !self.is_inside_reference(index, vx, vy) {
+                bail!("Motion vector ({}, {}) points outside the reference frame", vx, vy);
             }
         }
-        return Ok(());
+        return Ok(self.vectors[index]);
     }
-}
 
-impl From<BlockType> for u8 {
-    fn from(value: BlockType) -> Self {
-        match value {
-            BlockType::New => 255,
-            BlockType::Motion(x, y) => {
-                let x = x + 7;
-                let y = y + 7;
-                (((x & 0b1111) << 4) | (y & 0b1111)) as u8
+    // reads an entry, starting a repeat run if it is one
+    fn read_entry_at(&mut self, reader: &mut BitReader, index: usize) -> Result<()> {
+        let entry = read_entry(reader, self.predict_vector(index))?;
+        if let BlockType::Repeat(repeats) = entry {
+            if index == 0 {
+                bail!("Motion map starts with a repeat");
             }
-            BlockType::Repeat(r) => {
-                if r <= 16 {
-                    (0b11110000 | (r - 2)) as u8
-                } else {
-                    (((r - 17) << 4) | 0b1111) as u8
-                }
+            if index + repeats as usize > self.vectors.len() {
+                bail!("Motion map repeat runs past the end of the frame");
             }
+            self.run_left = repeats - 1;
+            self.vectors[index] = self.vectors[index - 1];
+        } else {
+            self.vectors[index] = entry;
+        }
+        return Ok(());
+    }
+}
+
+// one map entry, motion vectors relative to `predicted`, see MotionMap::write_vector
+fn write_entry(writer: &mut BitWriter, entry: BlockType, predicted: (i32, i32)) -> Result<()> {
+    match entry {
+        BlockType::Motion(x, y) => {
+            writer.write_ue(0)?;
+            writer.write_se(x - predicted.0)?;
+            writer.write_se(y - predicted.1)?;
+        }
+        BlockType::New => writer.write_ue(1)?,
+        BlockType::Repeat(count) => {
+            writer.write_ue(2)?;
+            writer.write_ue(count - 2)?;
         }
     }
+    return Ok(());
 }
 
-impl From<u8> for BlockType {
-    fn from(value: u8) -> Self {
-        let x = (value >> 4) as i32 - 7;
-        let y = (value & 0b1111) as i32 - 7;
-        if x == 8 && y == 8 {
-            return BlockType::New;
+fn read_entry(reader: &mut BitReader, predicted: (i32, i32)) -> Result<BlockType> {
+    let max_vector = MAX_SEARCH_RANGE as i32 * 4 + 3;
+    return match reader.read_ue()? {
+        0 => {
+            let x = predicted.0.saturating_add(reader.read_se()?);
+            let y = predicted.1.saturating_add(reader.read_se()?);
+            if x.abs() > max_vector || y.abs() > max_vector {
+                bail!("Motion vector ({}, {}) is out of range", x, y);
+            }
+            Ok(BlockType::Motion(x, y))
         }
-        if x == 8 {
-            return BlockType::Repeat((y + 9) as u32);
+        1 => Ok(BlockType::New),
+        2 => Ok(BlockType::Repeat(reader.read_ue()?.saturating_add(2))),
+        kind => bail!("Unknown motion map entry {}", kind),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 4x3 macroblock map
+    fn map() -> MotionMap {
+        return MotionMap::new(&VideoFrame::new(64, 48));
+    }
+
+    fn write_map(motion: &mut MotionMap) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut writer = BitWriter::new(&mut data);
+        for index in 0..motion.vectors.len() {
+            motion.write_vector(&mut writer, index).unwrap();
+        }
+        writer.flush().unwrap();
+        return data;
+    }
+
+    fn read_map(data: &[u8], motion: &mut MotionMap) -> Result<()> {
+        let mut data = data;
+        let mut reader = BitReader::new(&mut data);
+        for index in 0..motion.vectors.len() {
+            motion.read_vector(&mut reader, index)?;
+        }
+        return Ok(());
+    }
+
+    // a stream of raw entries: ue(kind) and its fields
+    fn raw_entries(entries: &[BlockType]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut writer = BitWriter::new(&mut data);
+        for entry in entries {
+            write_entry(&mut writer, *entry, (0, 0)).unwrap();
         }
-        if y == 8 {
-            return BlockType::Repeat((x + 24) as u32);
+        writer.flush().unwrap();
+        return data;
+    }
+
+    // quarter-size luma of a 128x64 frame, a texture shifted by `shift` pixels (or another texture)
+    fn scene(shift: u32, other: bool) -> Plane {
+        let mut plane = Plane::new(32, 16);
+        for y in 0..16 {
+            for x in 0..32 {
+                let u = (x + shift) as f64;
+                let value = if other {
+                    ((x / 2 + y / 2) % 2) as f64 * 255.0
+                } else {
+                    128.0 + 100.0 * (u / 3.0).sin() * (y as f64 / 2.0).cos()
+                };
+                plane.put(x, y, value);
+            }
         }
-        return BlockType::Motion(x, y);
+        return plane;
+    }
+
+    #[test]
+    fn scene_cuts_are_unmatched() {
+        assert_eq!(unmatched_ratio(&scene(0, false), &scene(0, false), 16), 0.0);
+        // a pan within the search range still matches, except where it uncovers new content
+        assert!(unmatched_ratio(&scene(0, false), &scene(3, false), 16) < 0.2);
+        assert_eq!(unmatched_ratio(&scene(0, true), &scene(0, false), 16), 1.0);
+    }
+
+    #[test]
+    fn vectors_are_predicted_from_the_median() {
+        let mut motion = map();
+        motion.vectors[0] = BlockType::Motion(4, 8);
+        motion.vectors[1] = BlockType::Motion(-4, 0);
+        motion.vectors[2] = BlockType::Motion(12, -8);
+        motion.vectors[3] = BlockType::New;
+        motion.vectors[4] = BlockType::Motion(0, 4);
+        // first row: the left neighbour, nothing for the first macroblock
+        assert_eq!(motion.predict_vector(0), (0, 0));
+        assert_eq!(motion.predict_vector(2), (-4, 0));
+        // left (0, 4), top (-4, 0), top-right (12, -8)
+        assert_eq!(motion.predict_vector(5), (0, 0));
+        // no left neighbour, top (4, 8), top-right (-4, 0)
+        assert_eq!(motion.predict_vector(4), (0, 0));
+        // last column: top-left instead of top-right, a new block counts as zero
+        motion.vectors[6] = BlockType::Motion(8, 8);
+        assert_eq!(motion.predict_vector(7), (8, 0));
+    }
+
+    #[test]
+    fn vectors_round_trip() {
+        let mut motion = map();
+        motion.vectors = vec![
+            BlockType::Motion(0, 0),
+            BlockType::Motion(-5, 3),
+            BlockType::Motion(-5, 3),
+            BlockType::Motion(-5, 3),
+            BlockType::New,
+            BlockType::Motion(-3, 2),
+            BlockType::Motion(-7, 9),
+            BlockType::New,
+            BlockType::New,
+            BlockType::Motion(-60, -63),
+            BlockType::Motion(3, -1),
+            BlockType::Motion(3, 3),
+        ];
+        let data = write_map(&mut motion);
+        let mut decoded = map();
+        read_map(&data, &mut decoded).unwrap();
+        assert_eq!(decoded.vectors, motion.vectors);
+    }
+
+    #[test]
+    fn long_runs_round_trip() {
+        let mut motion = MotionMap::new(&VideoFrame::new(640, 480));
+        motion.vectors.fill(BlockType::Motion(-2, 1));
+        motion.vectors[0] = BlockType::Motion(0, 1);
+        let data = write_map(&mut motion);
+        // one vector and one run for the rest of the frame
+        assert!(data.len() < 8);
+        let mut decoded = MotionMap::new(&VideoFrame::new(640, 480));
+        read_map(&data, &mut decoded).unwrap();
+        assert_eq!(decoded.vectors, motion.vectors);
+    }
+
+    #[test]
+    fn vectors_outside_the_reference_are_rejected() {
+        // the first macroblock moved a whole pixel left
+        let data = raw_entries(&[BlockType::Motion(-4, 0)]);
+        assert!(read_map(&data, &mut map()).is_err());
+        // the last one of the first row moved a pixel right, the rest are new
+        let mut entries = vec![BlockType::New; 3];
+        entries.push(BlockType::Motion(4, 0));
+        entries.extend([BlockType::New; 8]);
+        assert!(read_map(&raw_entries(&entries), &mut map()).is_err());
+        // a repeat carries a vector that fits the first macroblock past the edge
+        let data = raw_entries(&[BlockType::Motion(64 * 3, 0), BlockType::Repeat(2)]);
+        assert!(read_map(&data, &mut map()).is_err());
+        // sub-pixel overhang is what the encoder produces
+        let mut entries = vec![BlockType::Motion(-3, -3)];
+        entries.extend([BlockType::New; 11]);
+        assert!(read_map(&raw_entries(&entries), &mut map()).is_ok());
+    }
+
+    #[test]
+    fn malformed_maps_are_rejected() {
+        assert!(read_map(&raw_entries(&[BlockType::Repeat(2)]), &mut map()).is_err());
+        let data = raw_entries(&[BlockType::New, BlockType::Repeat(12)]);
+        assert!(read_map(&data, &mut map()).is_err());
+        let data = raw_entries(&[BlockType::Motion(MAX_SEARCH_RANGE as i32 * 4 + 4, 0)]);
+        assert!(read_map(&data, &mut map()).is_err());
+
+        let mut data = Vec::new();
+        let mut writer = BitWriter::new(&mut data);
+        writer.write_ue(3).unwrap();
+        writer.flush().unwrap();
+        assert!(read_map(&data, &mut map()).is_err());
+        // truncated
+        assert!(read_map(&raw_entries(&[BlockType::New; 4]), &mut map()).is_err());
     }
 }
@@ -5,6 +5,21 @@ use image::{GrayImage, ImageReader, Luma, Rgb, RgbImage};
 
 use crate::blocks::Block;
 
+// (1, -5, 20, 20, -5, 1) / 32 over the samples at offsets -2..=3
+fn six_tap(sample: impl Fn(i32) -> f64) -> f64 {
+    (sample(-2) - 5.0 * sample(-1) + 20.0 * sample(0) + 20.0 * sample(1) - 5.0 * sample(2) + sample(3)) / 32.0
+}
+
+// quarter-pixel sample from the two nearest half-pixel ones
+fn quarter_from_half(x: i32, y: i32, sample_half: impl Fn(i32, i32) -> f64) -> f64 {
+    if x & 1 == 0 && y & 1 == 0 {
+        return sample_half(x / 2, y / 2);
+    }
+    let a = sample_half(x.div_euclid(2), y.div_euclid(2));
+    let b = sample_half((x + 1).div_euclid(2), (y + 1).div_euclid(2));
+    (a + b) / 2.0
+}
+
 #[derive(Clone)]
 pub struct Plane {
     pub data: Vec<f64>,
@@ -53,6 +68,132 @@ impl Plane {
         self.data[(x + y * self.width) as usize]
     }
 
+    /// Variance of the `size` x `size` samples at (`x`, `y`).
+    pub fn block_variance(&self, x: u32, y: u32, size: u32) -> f64 {
+        let mut sum = 0.0;
+        let mut sum_sq = 0.0;
+        for i in 0..size {
+            let start = (x + (y + i) * self.width) as usize;
+            for value in &self.data[start..start + size as usize] {
+                sum += value;
+                sum_sq += value * value;
+            }
+        }
+        let count = (size * size) as f64;
+        let mean = sum / count;
+        (sum_sq / count - mean * mean).max(0.0)
+    }
+
+    /// Sample at (`x`, `y`), coordinates outside the plane are clamped to its edges.
+    pub fn get_clamped(&self, x: i32, y: i32) -> f64 {
+        let x = x.clamp(0, self.width as i32 - 1) as u32;
+        let y = y.clamp(0, self.height as i32 - 1) as u32;
+        self.data[(x + y * self.width) as usize]
+    }
+
+    // sample at (`x`, `y`) in half pixels, interpolated with the H.264 6-tap filter
+    fn sample_half(&self, x: i32, y: i32) -> f64 {
+        let ix = x.div_euclid(2);
+        let iy = y.div_euclid(2);
+        let value = match (x & 1, y & 1) {
+            (0, 0) => return self.get_clamped(ix, iy),
+            (1, 0) => six_tap(|i| self.get_clamped(ix + i, iy)),
+            (0, _) => six_tap(|i| self.get_clamped(ix, iy + i)),
+            _ => six_tap(|j| six_tap(|i| self.get_clamped(ix + i, iy + j))),
+        };
+        value.clamp(0.0, 255.0)
+    }
+
+    /// Sample at (`x`, `y`) in quarter pixels: half-pixel positions use the H.264 6-tap filter,
+    /// quarter-pixel positions average the two nearest half-pixel samples.
+    pub fn sample_quarter(&self, x: i32, y: i32) -> f64 {
+        quarter_from_half(x, y, |hx, hy| self.sample_half(hx, hy))
+    }
+
+    /// The plane interpolated to half pixels, twice the width and height,
+    /// for [`Plane::sample_quarter_upsampled`].
+    pub fn half_pel(&self) -> Plane {
+        // horizontal half-pixel samples before clamping, the centre positions filter them vertically
+        let mut horizontal = Plane::new(self.width, self.height);
+        for y in 0..self.height as i32 {
+            for x in 0..self.width as i32 {
+                horizontal.put(x as u32, y as u32, six_tap(|i| self.get_clamped(x + i, y)));
+            }
+        }
+        let mut result = Plane::new(self.width * 2, self.height * 2);
+        for y in 0..self.height as i32 {
+            for x in 0..self.width as i32 {
+                let (rx, ry) = (x as u32 * 2, y as u32 * 2);
+                result.put(rx, ry, self.get(x as u32, y as u32));
+                result.put(rx + 1, ry, horizontal.get(x as u32, y as u32).clamp(0.0, 255.0));
+                result.put(rx, ry + 1, six_tap(|j| self.get_clamped(x, y + j)).clamp(0.0, 255.0));
+                let center = six_tap(|j| horizontal.get_clamped(x, y + j));
+                result.put(rx + 1, ry + 1, center.clamp(0.0, 255.0));
+            }
+        }
+        result
+    }
+
+    /// Half the width and height, every sample the average of a 2x2 square. An odd last row
+    /// or column is averaged with itself.
+    pub fn downscale(&self) -> Plane {
+        let mut result = Plane::new(self.width.div_ceil(2), self.height.div_ceil(2));
+        for y in 0..result.height {
+            for x in 0..result.width {
+                let (sx, sy) = (x as i32 * 2, y as i32 * 2);
+                let sum = self.get_clamped(sx, sy)
+                    + self.get_clamped(sx + 1, sy)
+                    + self.get_clamped(sx, sy + 1)
+                    + self.get_clamped(sx + 1, sy + 1);
+                result.put(x, y, sum / 4.0);
+            }
+        }
+        result
+    }
+
+    /// Same as [`Plane::sample_quarter`] on the plane this one was made from by [`Plane::half_pel`], but faster.
+    pub fn sample_quarter_upsampled(&self, x: i32, y: i32) -> f64 {
+        quarter_from_half(x, y, |hx, hy| self.get_clamped(hx, hy))
+    }
+
+    /// Sample at (`x`, `y`) in eighth pixels, bilinear.
+    pub fn sample_eighth(&self, x: i32, y: i32) -> f64 {
+        let ix = x.div_euclid(8);
+        let iy = y.div_euclid(8);
+        let fx = x.rem_euclid(8) as f64 / 8.0;
+        let fy = y.rem_euclid(8) as f64 / 8.0;
+        let top = self.get_clamped(ix, iy) * (1.0 - fx) + self.get_clamped(ix + 1, iy) * fx;
+        let bottom = self.get_clamped(ix, iy + 1) * (1.0 - fx) + self.get_clamped(ix + 1, iy + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    // whether the 8x8 block at integer (`x`, `y`) lies inside the plane
+    fn contains_block(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x + 8 <= self.width as i32 && y + 8 <= self.height as i32
+    }
+
+    /// Extracts the 8x8 block at (`x`, `y`) in quarter pixels, see [`Plane::sample_quarter`].
+    /// Samples outside the plane are clamped to its edges.
+    pub fn extract_block_quarter(&self, x: i32, y: i32, block: &mut Block) {
+        if x & 3 == 0 && y & 3 == 0 && self.contains_block(x / 4, y / 4) {
+            return self.extract_block(x as u32 / 4, y as u32 / 4, block);
+        }
+        for (i, d) in block.0.iter_mut().enumerate() {
+            *d = self.sample_quarter(x + (i % 8) as i32 * 4, y + (i / 8) as i32 * 4);
+        }
+    }
+
+    /// Extracts the 8x8 block at (`x`, `y`) in eighth pixels, see [`Plane::sample_eighth`].
+    /// Samples outside the plane are clamped to its edges.
+    pub fn extract_block_eighth(&self, x: i32, y: i32, block: &mut Block) {
+        if x & 7 == 0 && y & 7 == 0 && self.contains_block(x / 8, y / 8) {
+            return self.extract_block(x as u32 / 8, y as u32 / 8, block);
+        }
+        for (i, d) in block.0.iter_mut().enumerate() {
+            *d = self.sample_eighth(x + (i % 8) as i32 * 8, y + (i / 8) as i32 * 8);
+        }
+    }
+
     pub fn plane2luma(plane: &Plane, image: &mut GrayImage) {
         for (input, output) in plane.data.iter().zip(image.pixels_mut()) {
             *output = Luma([*input as u8]);
@@ -75,3 +216,46 @@ impl Plane {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> Plane {
+        let mut plane = Plane::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                plane.put(x, y, (x * 7 + y * 3) as f64);
+            }
+        }
+        plane
+    }
+
+    #[test]
+    fn aligned_blocks_past_the_edge_are_clamped() {
+        let plane = gradient(16, 16);
+        let mut block = Block::new();
+        for (x, y) in [(12, 0), (0, 12), (64, 64), (-12, -4)] {
+            plane.extract_block_quarter(x * 4, y * 4, &mut block);
+            for (i, d) in block.0.iter().enumerate() {
+                assert_eq!(*d, plane.get_clamped(x + (i % 8) as i32, y + (i / 8) as i32));
+            }
+            plane.extract_block_eighth(x * 8, y * 8, &mut block);
+            for (i, d) in block.0.iter().enumerate() {
+                assert_eq!(*d, plane.get_clamped(x + (i % 8) as i32, y + (i / 8) as i32));
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_blocks_inside_match_extract_block() {
+        let plane = gradient(16, 16);
+        let mut expected = Block::new();
+        let mut block = Block::new();
+        plane.extract_block(8, 8, &mut expected);
+        plane.extract_block_quarter(32, 32, &mut block);
+        assert_eq!(block.0, expected.0);
+        plane.extract_block_eighth(64, 64, &mut block);
+        assert_eq!(block.0, expected.0);
+    }
+}
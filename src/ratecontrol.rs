@@ -0,0 +1,198 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    blocks::{MAX_QP, MIN_QP},
+    twopass::PassStats,
+    videocode::FrameType,
+};
+
+// bits per pixel at qp 0 assumed before the first frame of a type is coded
+const INITIAL_BPP: [f64; 3] = [3.0, 1.5, 2.0];
+// VBR spreads the buffer error over this many buffer lengths
+const VBR_HORIZON: f64 = 10.0;
+// second pass bit distribution, see PassStats::plan
+const QCOMP: f64 = 0.6;
+
+/// How [`RateController`] holds the target bitrate.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RateMode {
+    /// The stream never runs more than one buffer ahead of the target, quality follows the content.
+    Cbr,
+    /// The average over the sequence meets the target, complex parts may run over by more than a buffer.
+    Vbr,
+}
+
+impl FromStr for RateMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<RateMode> {
+        return match value.to_ascii_lowercase().as_str() {
+            "cbr" => Ok(RateMode::Cbr),
+            "vbr" => Ok(RateMode::Vbr),
+            _ => bail!("Unknown rate control mode \"{}\", expected cbr or vbr", value),
+        };
+    }
+}
+
+impl Display for RateMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            RateMode::Cbr => write!(f, "cbr"),
+            RateMode::Vbr => write!(f, "vbr"),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateConfig {
+    pub mode: RateMode,
+    /// Target bitrate, kbit/s.
+    pub bitrate: u32,
+    /// Buffer size, kbit: how far the stream may run ahead of the target bitrate.
+    pub buffer_size: u32,
+}
+
+impl RateConfig {
+    /// A config with a one second buffer.
+    pub fn new(mode: RateMode, bitrate: u32) -> RateConfig {
+        return RateConfig {
+            mode,
+            bitrate,
+            buffer_size: bitrate,
+        };
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.bitrate == 0 {
+            bail!("Target bitrate must be positive");
+        }
+        if self.buffer_size == 0 {
+            bail!("Rate control buffer size must be positive");
+        }
+        return Ok(());
+    }
+}
+
+/// Picks a quantizer parameter for every frame so that the stream meets a [`RateConfig`].
+///
+/// Frame size is modelled as `complexity * 2^(-qp / 6)`, with the complexity of each frame type
+/// estimated from the sizes of the frames already coded. The buffer holds the difference between
+/// the bits written and the bits the target bitrate allows so far.
+///
+/// With first pass stats the size of every frame is planned in advance, and the model only
+/// corrects the plan for the difference between the passes.
+pub struct RateController {
+    config: RateConfig,
+    frame_bits: f64,
+    buffer_bits: f64,
+    complexity: [f64; 3],
+    average_complexity: f64,
+    fullness: f64,
+    // (planned bits, first pass complexity) by display index
+    plan: Vec<(f64, f64)>,
+    // ratio between the complexities of this pass and the first
+    plan_scale: f64,
+    frames_coded: usize,
+}
+
+fn qp_scale(qp: i32) -> f64 {
+    return 2f64.powf(qp as f64 / 6.0);
+}
+
+impl RateController {
+    pub fn new(config: RateConfig, fps: f32, width: u32, height: u32) -> Result<RateController> {
+        config.validate()?;
+        if fps <= 0.0 {
+            bail!("Rate control needs a positive frame rate");
+        }
+        let pixels = (width * height) as f64;
+        let complexity = INITIAL_BPP.map(|bpp| bpp * pixels);
+        return Ok(RateController {
+            config,
+            frame_bits: config.bitrate as f64 * 1000.0 / fps as f64,
+            buffer_bits: config.buffer_size as f64 * 1000.0,
+            complexity,
+            average_complexity: complexity[FrameType::PFrame as usize],
+            fullness: 0.0,
+            plan: Vec::new(),
+            plan_scale: 1.0,
+            frames_coded: 0,
+        });
+    }
+
+    /// Second pass controller: spends the target bitrate over the whole sequence as measured by `stats`.
+    pub fn with_first_pass(config: RateConfig, fps: f32, stats: &PassStats) -> Result<RateController> {
+        let mut result = RateController::new(config, fps, stats.width, stats.height)?;
+        let total_bits = result.frame_bits * stats.frames.len() as f64;
+        let planned_bits = stats.plan(total_bits, QCOMP);
+        result.plan = vec![(0.0, 0.0); planned_bits.len()];
+        for frame in &stats.frames {
+            if let Some(entry) = result.plan.get_mut(frame.display_index as usize) {
+                *entry = (planned_bits[frame.display_index as usize], frame.complexity());
+            }
+        }
+        return Ok(result);
+    }
+
+    pub fn config(&self) -> &RateConfig {
+        return &self.config;
+    }
+
+    /// Bits written beyond the target so far, negative when the stream is under budget.
+    pub fn fullness(&self) -> f64 {
+        return self.fullness;
+    }
+
+    /// Quantizer parameter for the next frame.
+    pub fn frame_qp(&self, frame_type: FrameType, display_index: u32) -> i32 {
+        let (complexity, mut target) = match self.plan.get(display_index as usize) {
+            Some((bits, complexity)) if *complexity > 0.0 => (complexity * self.plan_scale, *bits),
+            _ => {
+                // frame types share the budget by complexity, which gives them all the same qp
+                let complexity = self.complexity[frame_type as usize];
+                (complexity, self.frame_bits * complexity / self.average_complexity)
+            }
+        };
+        let mut horizon = match self.config.mode {
+            RateMode::Cbr => self.buffer_bits / self.frame_bits,
+            RateMode::Vbr => self.buffer_bits / self.frame_bits * VBR_HORIZON,
+        };
+        if !self.plan.is_empty() {
+            // the budget has to be met by the end of the sequence
+            horizon = horizon.min(self.plan.len().saturating_sub(self.frames_coded) as f64);
+        }
+        target -= self.fullness / horizon.max(1.0);
+        if self.config.mode == RateMode::Cbr {
+            target = target.min(self.buffer_bits - self.fullness);
+        }
+        target = target.max(self.frame_bits / 8.0);
+
+        let qp = (6.0 * (complexity / target).log2()).round() as i32;
+        return qp.clamp(MIN_QP, MAX_QP);
+    }
+
+    /// Updates the model with the size of a frame coded with `qp`.
+    pub fn update(&mut self, frame_type: FrameType, display_index: u32, qp: i32, bytes: u64) {
+        let bits = (bytes * 8) as f64;
+        let complexity = bits * qp_scale(qp);
+        if let Some((_, planned_complexity)) = self.plan.get(display_index as usize) {
+            if *planned_complexity > 0.0 {
+                self.plan_scale = self.plan_scale * 0.8 + complexity / planned_complexity * 0.2;
+            }
+        }
+        let type_complexity = &mut self.complexity[frame_type as usize];
+        *type_complexity = (*type_complexity + complexity) / 2.0;
+        self.average_complexity = self.average_complexity * 0.8 + complexity * 0.2;
+
+        self.frames_coded += 1;
+        self.fullness += bits - self.frame_bits;
+        // unused bandwidth can't be saved up beyond the buffer
+        let limit = match self.config.mode {
+            RateMode::Cbr => self.buffer_bits,
+            RateMode::Vbr => self.buffer_bits * VBR_HORIZON,
+        };
+        self.fullness = self.fullness.max(-limit);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    frameio::{load_plane, load_plane_subsampled, store_plane, store_plane_upsampled, FrameSink, FrameSource},
+    videocode::VideoFrame,
+};
+
+/// Sample layout of headerless YUV frames.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// Planar 4:2:0, Y then U then V.
+    I420,
+    /// 4:2:0 with a Y plane followed by one interleaved UV plane.
+    Nv12,
+    /// Planar 4:4:4, chroma is averaged down on input.
+    Yuv444p,
+}
+
+impl PixelFormat {
+    /// Chroma plane size for a `width` x `height` frame.
+    pub fn chroma_size(&self, width: u32, height: u32) -> (u32, u32) {
+        return match self {
+            PixelFormat::I420 | PixelFormat::Nv12 => (width.div_ceil(2), height.div_ceil(2)),
+            PixelFormat::Yuv444p => (width, height),
+        };
+    }
+
+    /// Bytes in one frame.
+    pub fn frame_size(&self, width: u32, height: u32) -> usize {
+        let (chroma_width, chroma_height) = self.chroma_size(width, height);
+        return (width * height + chroma_width * chroma_height * 2) as usize;
+    }
+}
+
+impl FromStr for PixelFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<PixelFormat> {
+        return match value.to_ascii_lowercase().as_str() {
+            "i420" | "yuv420p" => Ok(PixelFormat::I420),
+            "nv12" => Ok(PixelFormat::Nv12),
+            "yuv444p" => Ok(PixelFormat::Yuv444p),
+            _ => bail!("Unknown pixel format \"{}\", expected i420, nv12 or yuv444p", value),
+        };
+    }
+}
+
+impl Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            PixelFormat::I420 => write!(f, "i420"),
+            PixelFormat::Nv12 => write!(f, "nv12"),
+            PixelFormat::Yuv444p => write!(f, "yuv444p"),
+        };
+    }
+}
+
+/// Reads headerless YUV frames of a known size and format straight into the frame planes.
+pub struct RawYuvReader<R: Read> {
+    reader: R,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    data: Vec<u8>,
+    chroma: Vec<u8>,
+}
+
+/// Writes frames as headerless YUV.
+pub struct RawYuvWriter<W: Write> {
+    writer: W,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    data: Vec<u8>,
+    chroma: Vec<u8>,
+}
+
+impl<R: Read> RawYuvReader<R> {
+    pub fn new(reader: R, width: u32, height: u32, format: PixelFormat) -> Result<RawYuvReader<R>> {
+        if width == 0 || height == 0 {
+            bail!("Bad raw frame size {}x{}", width, height);
+        }
+        return Ok(RawYuvReader {
+            reader,
+            width,
+            height,
+            format,
+            data: vec![0; format.frame_size(width, height)],
+            chroma: Vec::new(),
+        });
+    }
+
+    /// Fills the frame buffer, returns `false` if the stream ended before the first byte.
+    fn read_data(&mut self) -> Result<bool> {
+        let mut filled = 0;
+        while filled < self.data.len() {
+            let count = self.reader.read(&mut self.data[filled..])?;
+            if count == 0 {
+                if filled == 0 {
+                    return Ok(false);
+                }
+                bail!("Truncated raw frame: {} of {} bytes", filled, self.data.len());
+            }
+            filled += count;
+        }
+        return Ok(true);
+    }
+}
+
+impl<R: Read> FrameSource for RawYuvReader<R> {
+    fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    fn fps(&self) -> Option<f32> {
+        return None;
+    }
+
+    fn frame_count(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn read_frame(&mut self, frame: &mut VideoFrame) -> Result<bool> {
+        if !self.read_data()? {
+            return Ok(false);
+        }
+
+        let luma_size = (self.width * self.height) as usize;
+        let (chroma_width, chroma_height) = self.format.chroma_size(self.width, self.height);
+        let chroma_size = (chroma_width * chroma_height) as usize;
+        let (y_data, uv_data) = self.data.split_at(luma_size);
+        load_plane(&mut frame.y_plane, y_data, self.width, self.height, self.width);
+        match self.format {
+            PixelFormat::I420 => {
+                let (u_data, v_data) = uv_data.split_at(chroma_size);
+                load_plane(&mut frame.u_plane, u_data, chroma_width, chroma_height, chroma_width);
+                load_plane(&mut frame.v_plane, v_data, chroma_width, chroma_height, chroma_width);
+            }
+            PixelFormat::Nv12 => {
+                self.chroma.clear();
+                self.chroma.extend(uv_data.iter().step_by(2));
+                self.chroma.extend(uv_data.iter().skip(1).step_by(2));
+                let (u_data, v_data) = self.chroma.split_at(chroma_size);
+                load_plane(&mut frame.u_plane, u_data, chroma_width, chroma_height, chroma_width);
+                load_plane(&mut frame.v_plane, v_data, chroma_width, chroma_height, chroma_width);
+            }
+            PixelFormat::Yuv444p => {
+                let (u_data, v_data) = uv_data.split_at(chroma_size);
+                load_plane_subsampled(&mut frame.u_plane, u_data, chroma_width, chroma_height, chroma_width);
+                load_plane_subsampled(&mut frame.v_plane, v_data, chroma_width, chroma_height, chroma_width);
+            }
+        }
+        return Ok(true);
+    }
+}
+
+impl<W: Write> RawYuvWriter<W> {
+    pub fn new(writer: W, width: u32, height: u32, format: PixelFormat) -> RawYuvWriter<W> {
+        return RawYuvWriter {
+            writer,
+            width,
+            height,
+            format,
+            data: Vec::new(),
+            chroma: Vec::new(),
+        };
+    }
+}
+
+impl<W: Write> FrameSink for RawYuvWriter<W> {
+    fn write_frame(&mut self, _index: u32, frame: &VideoFrame) -> Result<()> {
+        let (chroma_width, chroma_height) = self.format.chroma_size(self.width, self.height);
+        self.data.clear();
+        store_plane(&frame.y_plane, &mut self.data, self.width, self.height);
+        match self.format {
+            PixelFormat::I420 => {
+                store_plane(&frame.u_plane, &mut self.data, chroma_width, chroma_height);
+                store_plane(&frame.v_plane, &mut self.data, chroma_width, chroma_height);
+            }
+            PixelFormat::Nv12 => {
+                self.chroma.clear();
+                store_plane(&frame.u_plane, &mut self.chroma, chroma_width, chroma_height);
+                store_plane(&frame.v_plane, &mut self.chroma, chroma_width, chroma_height);
+                let (u_data, v_data) = self.chroma.split_at(self.chroma.len() / 2);
+                for (u, v) in u_data.iter().zip(v_data) {
+                    self.data.push(*u);
+                    self.data.push(*v);
+                }
+            }
+            PixelFormat::Yuv444p => {
+                store_plane_upsampled(&frame.u_plane, &mut self.data, chroma_width, chroma_height);
+                store_plane_upsampled(&frame.v_plane, &mut self.data, chroma_width, chroma_height);
+            }
+        }
+
+        self.writer.write_all(&self.data)?;
+        self.writer.flush()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn frame(width: u32, height: u32, seed: u32) -> VideoFrame {
+        let mut frame = VideoFrame::new(width, height);
+        for (index, plane) in [&mut frame.y_plane, &mut frame.u_plane, &mut frame.v_plane]
+            .into_iter()
+            .enumerate()
+        {
+            for y in 0..plane.height() {
+                for x in 0..plane.width() {
+                    plane.put(x, y, ((x * 7 + y * 13 + seed * 31 + index as u32 * 50) % 256) as f64);
+                }
+            }
+        }
+        return frame;
+    }
+
+    fn round_trip(format: PixelFormat) {
+        let (width, height) = (21, 17);
+        let frames: Vec<VideoFrame> = (0..3).map(|seed| frame(width, height, seed)).collect();
+
+        let mut data = Vec::new();
+        let mut writer = RawYuvWriter::new(&mut data, width, height, format);
+        for (index, frame) in frames.iter().enumerate() {
+            writer.write_frame(index as u32, frame).unwrap();
+        }
+        assert_eq!(data.len(), format.frame_size(width, height) * frames.len());
+        if format == PixelFormat::Nv12 {
+            let luma_size = (width * height) as usize;
+            assert_eq!(data[luma_size..luma_size + 4], [50, 100, 57, 107]);
+        }
+
+        let mut reader = RawYuvReader::new(Cursor::new(data), width, height, format).unwrap();
+        let mut decoded = VideoFrame::new(width, height);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        for frame in &frames {
+            assert!(reader.read_frame(&mut decoded).unwrap());
+            for (a, b, width, height) in [
+                (&frame.y_plane, &decoded.y_plane, width, height),
+                (&frame.u_plane, &decoded.u_plane, chroma_width, chroma_height),
+                (&frame.v_plane, &decoded.v_plane, chroma_width, chroma_height),
+            ] {
+                for y in 0..height {
+                    for x in 0..width {
+                        assert_eq!(a.get(x, y), b.get(x, y), "{} pixel {},{} differs", format, x, y);
+                    }
+                }
+            }
+        }
+        assert!(!reader.read_frame(&mut decoded).unwrap());
+    }
+
+    #[test]
+    fn i420_round_trip() {
+        round_trip(PixelFormat::I420);
+    }
+
+    #[test]
+    fn nv12_round_trip() {
+        round_trip(PixelFormat::Nv12);
+    }
+
+    #[test]
+    fn yuv444p_round_trip() {
+        round_trip(PixelFormat::Yuv444p);
+    }
+}
@@ -0,0 +1,442 @@
+use std::io::{Seek, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    blocks::QMatrices,
+    container::FrameIndex,
+    motion::{self, MotionSearch, DEFAULT_SEARCH_RANGE},
+    planes::Plane,
+    ratecontrol::RateController,
+    videocode::{Encoder, FrameType, VideoFrame},
+};
+
+/// Frame type layout for [`SequenceEncoder`].
+#[derive(Clone, Copy, Debug)]
+pub struct GopConfig {
+    /// B-frames between two anchors, 0 codes every frame in display order (IPPP).
+    pub b_frames: usize,
+    /// Maximum distance between two I-frames, in frames.
+    pub gop_length: usize,
+    /// Frames whose display index is a multiple of this are always I-frames.
+    pub keyframe_interval: Option<usize>,
+    /// Scene-cut sensitivity, 0..=100, 0 disables detection. A frame starts a new GOP when
+    /// more than `100 - scene_cut` percent of its macroblocks find no match in the previous frame.
+    pub scene_cut: u32,
+}
+
+impl Default for GopConfig {
+    fn default() -> GopConfig {
+        return GopConfig {
+            b_frames: 2,
+            gop_length: 33,
+            keyframe_interval: None,
+            scene_cut: 40,
+        };
+    }
+}
+
+impl GopConfig {
+    /// No B-frames, so no frame waits for a later one: IPPP...
+    pub fn low_delay() -> GopConfig {
+        return GopConfig {
+            b_frames: 0,
+            ..GopConfig::default()
+        };
+    }
+
+    /// Every frame is an I-frame.
+    pub fn intra_only() -> GopConfig {
+        return GopConfig {
+            b_frames: 0,
+            gop_length: 1,
+            keyframe_interval: None,
+            scene_cut: 0,
+        };
+    }
+
+    pub fn is_intra_only(&self) -> bool {
+        return self.keyframe_interval == Some(1) || (self.gop_length == 1 && self.b_frames == 0);
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.gop_length == 0 {
+            bail!("GOP length must be at least 1");
+        }
+        if self.keyframe_interval == Some(0) {
+            bail!("Keyframe interval must be at least 1");
+        }
+        if self.scene_cut > 100 {
+            bail!("Scene-cut sensitivity must be in 0..=100");
+        }
+        return Ok(());
+    }
+
+    fn is_forced_keyframe(&self, display_index: usize) -> bool {
+        return self
+            .keyframe_interval
+            .is_some_and(|interval| display_index.is_multiple_of(interval));
+    }
+}
+
+/// Size and type of one coded frame.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    pub display_index: u32,
+    pub frame_type: FrameType,
+    /// Quantizer parameter the frame was coded with.
+    pub qp: i32,
+    /// Bytes written after the size prefix.
+    pub size: u64,
+    /// Luma SAD left after prediction, see [`Encoder::motion_sad`].
+    pub motion_sad: f64,
+}
+
+/// Takes frames in display order, decides their types according to a [`GopConfig`] and writes them
+/// in coding order, each anchor followed by the B-frames before it. Builds the [`FrameIndex`] as it goes.
+pub struct SequenceEncoder<W: Write + Seek> {
+    writer: W,
+    config: GopConfig,
+    i_matrices: QMatrices,
+    pb_matrices: QMatrices,
+    coder: Encoder,
+    index: FrameIndex,
+    // frames after the last anchor, in display order
+    pending: Vec<VideoFrame>,
+    pending_count: usize,
+    // quarter-size luma of the last frame and the motion search range, for scene-cut detection
+    scene_plane: Plane,
+    search_range: u32,
+    // anchors as the decoder sees them, used as references
+    prev_recon: VideoFrame,
+    next_recon: VideoFrame,
+    frame_count: usize,
+    last_keyframe: usize,
+    rate: Option<RateController>,
+}
+
+impl<W: Write + Seek> SequenceEncoder<W> {
+    /// `writer` should be positioned after the container header, metadata and matrices.
+    /// I-frames are quantized with `i_matrices`, P- and B-frames with `pb_matrices`.
+    pub fn new(
+        writer: W,
+        width: u32,
+        height: u32,
+        config: GopConfig,
+        i_matrices: QMatrices,
+        pb_matrices: QMatrices,
+    ) -> Result<SequenceEncoder<W>> {
+        config.validate()?;
+        let recon = VideoFrame::new(width, height);
+        return Ok(SequenceEncoder {
+            writer,
+            config,
+            i_matrices,
+            pb_matrices,
+            coder: Encoder::new(),
+            index: FrameIndex::new(),
+            pending: Vec::new(),
+            pending_count: 0,
+            scene_plane: Plane::new(0, 0),
+            search_range: DEFAULT_SEARCH_RANGE,
+            prev_recon: recon.clone(),
+            next_recon: recon,
+            frame_count: 0,
+            last_keyframe: 0,
+            rate: None,
+        });
+    }
+
+    pub fn config(&self) -> &GopConfig {
+        return &self.config;
+    }
+
+    /// Lets `rate` pick the quantizer of every following frame instead of the fixed one
+    /// set by [`SequenceEncoder::set_qp`].
+    pub fn set_rate_control(&mut self, rate: RateController) {
+        self.rate = Some(rate);
+    }
+
+    pub fn rate_control(&self) -> Option<&RateController> {
+        return self.rate.as_ref();
+    }
+
+    /// Quantizer parameter for all frames when there is no rate control, see [`QMatrices::scaled`].
+    pub fn set_qp(&mut self, qp: i32) {
+        self.coder.set_qp(qp);
+    }
+
+    /// Adaptive quantization strength, see [`Encoder::set_aq_strength`].
+    pub fn set_aq_strength(&mut self, strength: f64) {
+        self.coder.set_aq_strength(strength);
+    }
+
+    /// Motion search range in pixels, see [`crate::motion::MotionMap::set_search_range`].
+    pub fn set_search_range(&mut self, range: u32) {
+        self.coder.set_search_range(range);
+        self.search_range = range;
+    }
+
+    /// Integer motion search, see [`crate::motion::MotionMap::set_search`].
+    pub fn set_search(&mut self, search: MotionSearch) {
+        self.coder.set_search(search);
+    }
+
+    /// Number of frames passed to [`SequenceEncoder::push_frame`] so far.
+    pub fn frame_count(&self) -> usize {
+        return self.frame_count;
+    }
+
+    /// Adds the next frame. B-frame candidates are held back until their anchor arrives,
+    /// so the returned list has an entry for every frame written by this call, possibly none.
+    pub fn push_frame(&mut self, frame: &VideoFrame) -> Result<Vec<FrameStats>> {
+        let display_index = self.frame_count;
+        let scene_cut = self.is_scene_cut(frame);
+        // frames before a cut get the last of them as their anchor, nothing is predicted across the cut
+        let mut stats = if scene_cut && self.pending_count > 0 {
+            self.encode_pending(false)?
+        } else {
+            Vec::new()
+        };
+
+        self.frame_count += 1;
+        if self.pending.len() > self.pending_count {
+            self.pending[self.pending_count].clone_from(frame);
+        } else {
+            self.pending.push(frame.clone());
+        }
+        self.pending_count += 1;
+
+        let keyframe = display_index == 0 || scene_cut || self.config.is_forced_keyframe(display_index);
+        if keyframe || self.pending_count > self.config.b_frames {
+            stats.extend(self.encode_pending(keyframe)?);
+        }
+        return Ok(stats);
+    }
+
+    /// Writes the frames still held back and the index, returns the writer positioned after the index.
+    pub fn finish(mut self) -> Result<(W, Vec<FrameStats>)> {
+        let stats = if self.pending_count > 0 {
+            self.encode_pending(false)?
+        } else {
+            Vec::new()
+        };
+        let index_offset = self.writer.stream_position()?;
+        self.index.write(&mut self.writer, index_offset)?;
+        return Ok((self.writer, stats));
+    }
+
+    // Compares `frame` with the source frame before it in display order, not with the reconstructed
+    // reference the encoder will use: a cut is a property of the content. See motion::unmatched_ratio.
+    fn is_scene_cut(&mut self, frame: &VideoFrame) -> bool {
+        if self.config.scene_cut == 0 || self.config.is_intra_only() {
+            return false;
+        }
+        let plane = frame.y_plane.downscale().downscale();
+        let scene_cut = self.frame_count > 0
+            && motion::unmatched_ratio(&plane, &self.scene_plane, self.search_range)
+                > 1.0 - self.config.scene_cut as f64 / 100.0;
+        self.scene_plane = plane;
+        return scene_cut;
+    }
+
+    // the last pending frame becomes the anchor, the ones before it B-frames
+    fn encode_pending(&mut self, keyframe: bool) -> Result<Vec<FrameStats>> {
+        let b_count = self.pending_count - 1;
+        let anchor_index = self.frame_count - 1;
+        let mut stats = Vec::with_capacity(self.pending_count);
+
+        if keyframe || anchor_index - self.last_keyframe >= self.config.gop_length {
+            self.start_frame(FrameType::IFrame, anchor_index)?;
+            let size = self.coder.encode_i_frame(
+                &self.pending[b_count],
+                &mut self.writer,
+                &self.i_matrices,
+                &mut self.next_recon,
+            )?;
+            stats.push(self.end_frame(FrameType::IFrame, anchor_index, size));
+            self.last_keyframe = anchor_index;
+        } else {
+            self.start_frame(FrameType::PFrame, anchor_index)?;
+            let size = self.coder.encode_p_frame(
+                &self.pending[b_count],
+                &self.prev_recon,
+                &mut self.writer,
+                &self.pb_matrices,
+                &mut self.next_recon,
+            )?;
+            stats.push(self.end_frame(FrameType::PFrame, anchor_index, size));
+        }
+
+        for b_index in 0..b_count {
+            let display_index = anchor_index - b_count + b_index;
+            self.start_frame(FrameType::BFrame, display_index)?;
+            let size = self.coder.encode_b_frame(
+                &self.pending[b_index],
+                &self.prev_recon,
+                &self.next_recon,
+                &mut self.writer,
+                &self.pb_matrices,
+            )?;
+            stats.push(self.end_frame(FrameType::BFrame, display_index, size));
+        }
+
+        std::mem::swap(&mut self.prev_recon, &mut self.next_recon);
+        self.pending_count = 0;
+        return Ok(stats);
+    }
+
+    fn start_frame(&mut self, frame_type: FrameType, display_index: usize) -> Result<()> {
+        let offset = self.writer.stream_position()?;
+        self.index.push(offset, frame_type, display_index as u32);
+        if let Some(rate) = &self.rate {
+            self.coder.set_qp(rate.frame_qp(frame_type, display_index as u32));
+        }
+        return Ok(());
+    }
+
+    fn end_frame(&mut self, frame_type: FrameType, display_index: usize, size: u64) -> FrameStats {
+        let qp = self.coder.qp();
+        if let Some(rate) = &mut self.rate {
+            rate.update(frame_type, display_index as u32, qp, size);
+        }
+        return FrameStats {
+            display_index: display_index as u32,
+            frame_type,
+            qp,
+            size,
+            motion_sad: self.coder.motion_sad(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        container::{ContainerHeader, ContainerReader, Metadata},
+        videocode::{
+            Decoder,
+            FrameType::{BFrame, IFrame},
+        },
+    };
+
+    // moving texture, so P- and B-frames carry motion and residuals
+    fn frame(width: u32, height: u32, t: f64) -> VideoFrame {
+        let mut frame = VideoFrame::new(width, height);
+        for (plane, scale) in [
+            (&mut frame.y_plane, 1.0),
+            (&mut frame.u_plane, 2.0),
+            (&mut frame.v_plane, 2.0),
+        ] {
+            for y in 0..plane.height() {
+                for x in 0..plane.width() {
+                    let (u, v) = (x as f64 * scale - t * 2.0, y as f64 * scale + t);
+                    let value = 128.0 + 60.0 * (u / 5.0).sin() * (v / 7.0).cos() + 40.0 * ((u + v) / 11.0).sin();
+                    plane.put(x, y, value.round());
+                }
+            }
+        }
+        return frame;
+    }
+
+    #[test]
+    fn one_frame_gops_with_b_frames_are_not_intra_only() {
+        assert!(GopConfig::intra_only().is_intra_only());
+        let config = GopConfig {
+            gop_length: 1,
+            ..GopConfig::default()
+        };
+        assert!(!config.is_intra_only());
+        let config = GopConfig {
+            keyframe_interval: Some(1),
+            ..GopConfig::default()
+        };
+        assert!(config.is_intra_only());
+
+        // every anchor is an I-frame, the frames between them are still B-frames
+        let config = GopConfig {
+            gop_length: 1,
+            scene_cut: 0,
+            ..GopConfig::default()
+        };
+        let matrices = QMatrices::new(0.9);
+        let mut coder =
+            SequenceEncoder::new(Cursor::new(Vec::new()), 32, 32, config, matrices.clone(), matrices).unwrap();
+        let mut types = Vec::new();
+        for _ in 0..7 {
+            types.extend(coder.push_frame(&VideoFrame::new(32, 32)).unwrap());
+        }
+        types.extend(coder.finish().unwrap().1);
+        let types: Vec<FrameType> = types.iter().map(|stats| stats.frame_type).collect();
+        assert_eq!(types, [IFrame, IFrame, BFrame, BFrame, IFrame, BFrame, BFrame]);
+    }
+
+    #[test]
+    fn seeking_gives_the_same_frames_as_a_full_decode() {
+        let (width, height, count) = (48, 32, 12);
+        let config = GopConfig {
+            gop_length: 5,
+            scene_cut: 0,
+            ..GopConfig::default()
+        };
+        let matrices = QMatrices::new(0.9);
+        let mut file = Cursor::new(Vec::new());
+        let mut header = ContainerHeader::new(width, height, 25.0, 0);
+        header.write(&mut file).unwrap();
+        Metadata::new().write(&mut file).unwrap();
+        matrices.write(&mut file).unwrap();
+        matrices.write(&mut file).unwrap();
+        let mut coder = SequenceEncoder::new(file, width, height, config, matrices.clone(), matrices).unwrap();
+        for t in 0..count {
+            coder.push_frame(&frame(width, height, t as f64)).unwrap();
+        }
+        let (mut file, _) = coder.finish().unwrap();
+        header.frame_count = count;
+        file.set_position(0);
+        header.write(&mut file).unwrap();
+        let data = file.into_inner();
+
+        let open = || ContainerReader::open(Cursor::new(data.clone())).unwrap();
+        assert!(open().index.keyframe_count() > 1);
+        let full: Vec<VideoFrame> = Decoder::new(open())
+            .map(|frame| frame.unwrap())
+            .enumerate()
+            .map(|(position, (index, frame))| {
+                assert_eq!(index, position as u32);
+                frame
+            })
+            .collect();
+        assert_eq!(full.len(), count as usize);
+
+        for display_index in 0..count {
+            let mut reader = open();
+            let entry = reader.seek_to_frame(display_index).unwrap();
+            assert_eq!(entry.frame_type, IFrame);
+            assert!(entry.display_index <= display_index);
+
+            let mut decoder = Decoder::new(open());
+            decoder.seek_to_frame(display_index).unwrap();
+            let (index, decoded) = decoder.next_frame().unwrap().unwrap();
+            assert_eq!(index, display_index);
+            let expected = &full[display_index as usize];
+            assert!(
+                decoded.y_plane.data == expected.y_plane.data,
+                "luma of frame {} differs",
+                index
+            );
+            assert!(
+                decoded.u_plane.data == expected.u_plane.data,
+                "U of frame {} differs",
+                index
+            );
+            assert!(
+                decoded.v_plane.data == expected.v_plane.data,
+                "V of frame {} differs",
+                index
+            );
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{sequence::FrameStats, videocode::FrameType};
+
+const STATS_SIGNATURE: &str = "#rvc2-pass1";
+
+/// Quantizer parameter of every frame in a first pass, so that frame sizes compare across the
+/// sequence: four times the quantizer steps of qp 0, in the middle of the useful range.
+pub const FIRST_PASS_QP: i32 = 12;
+
+/// One frame of a first pass.
+#[derive(Clone, Copy, Debug)]
+pub struct PassFrame {
+    pub display_index: u32,
+    pub frame_type: FrameType,
+    pub qp: i32,
+    /// Coded size in bytes at `qp`.
+    pub size: u64,
+    /// Luma SAD after prediction, intra prediction for I-frames.
+    pub motion_sad: f64,
+}
+
+impl PassFrame {
+    /// Size in bits the frame would take at qp 0, see [`crate::blocks::QMatrices::scaled`].
+    pub fn complexity(&self) -> f64 {
+        return (self.size * 8) as f64 * 2f64.powf(self.qp as f64 / 6.0);
+    }
+}
+
+impl From<&FrameStats> for PassFrame {
+    fn from(stats: &FrameStats) -> PassFrame {
+        return PassFrame {
+            display_index: stats.display_index,
+            frame_type: stats.frame_type,
+            qp: stats.qp,
+            size: stats.size,
+            motion_sad: stats.motion_sad,
+        };
+    }
+}
+
+/// Per-frame results of a first pass, stored as text: a signature line with the frame size,
+/// then one line per frame in coding order, "display_index type qp size motion_sad".
+pub struct PassStats {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<PassFrame>,
+}
+
+impl PassStats {
+    pub fn new(width: u32, height: u32) -> PassStats {
+        return PassStats {
+            width,
+            height,
+            frames: Vec::new(),
+        };
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        writeln!(writer, "{} {}x{}", STATS_SIGNATURE, self.width, self.height)?;
+        for frame in &self.frames {
+            let frame_type = match frame.frame_type {
+                FrameType::IFrame => 'I',
+                FrameType::PFrame => 'P',
+                FrameType::BFrame => 'B',
+            };
+            writeln!(
+                writer,
+                "{} {} {} {} {:.0}",
+                frame.display_index, frame_type, frame.qp, frame.size, frame.motion_sad
+            )?;
+        }
+        writer.flush()?;
+        return Ok(());
+    }
+
+    pub fn read<P: AsRef<Path>>(filename: P) -> Result<PassStats> {
+        let mut lines = BufReader::new(File::open(filename)?).lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let Some(size) = header.strip_prefix(STATS_SIGNATURE) else {
+            bail!("Not a first pass stats file");
+        };
+        let Some((width, height)) = size.trim().split_once('x') else {
+            bail!("Bad frame size in stats file");
+        };
+        let mut result = PassStats::new(width.parse()?, height.parse()?);
+
+        for (line_number, line) in lines.enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let context = || format!("Bad stats line {}", line_number + 2);
+            let [display_index, frame_type, qp, size, motion_sad] = fields[..] else {
+                bail!(context());
+            };
+            let frame_type = match frame_type {
+                "I" => FrameType::IFrame,
+                "P" => FrameType::PFrame,
+                "B" => FrameType::BFrame,
+                _ => bail!(context()),
+            };
+            result.frames.push(PassFrame {
+                display_index: display_index.parse().with_context(context)?,
+                frame_type,
+                qp: qp.parse().with_context(context)?,
+                size: size.parse().with_context(context)?,
+                motion_sad: motion_sad.parse().with_context(context)?,
+            });
+        }
+        return Ok(result);
+    }
+
+    /// Bits per frame, indexed by display index, that spend `total_bits` over the sequence.
+    ///
+    /// The luma SAD left after prediction measures how much a frame changes and moves. Each frame
+    /// gets a quantizer step proportional to `sad^(1 - qcomp)`, and so a share of the bits of
+    /// `complexity * sad^(qcomp - 1)`, see [`PassFrame::complexity`]. A qcomp of 1 gives every frame
+    /// the same qp. Lower values quantize busy frames, where detail is masked by motion, coarser,
+    /// and spend the bits on static ones. SADs below one level per pixel count as one.
+    pub fn plan(&self, total_bits: f64, qcomp: f64) -> Vec<f64> {
+        let min_sad = (self.width * self.height) as f64;
+        let mut weights = vec![0.0; self.frames.len()];
+        for frame in &self.frames {
+            if let Some(weight) = weights.get_mut(frame.display_index as usize) {
+                *weight = frame.complexity().max(1.0) * frame.motion_sad.max(min_sad).powf(qcomp - 1.0);
+            }
+        }
+        let weight_sum: f64 = weights.iter().sum();
+        return weights.iter().map(|weight| total_bits * weight / weight_sum).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(display_index: u32, size: u64, motion_sad: f64) -> PassFrame {
+        return PassFrame {
+            display_index,
+            frame_type: FrameType::PFrame,
+            qp: FIRST_PASS_QP,
+            size,
+            motion_sad,
+        };
+    }
+
+    #[test]
+    fn plan_spends_the_budget() {
+        let mut stats = PassStats::new(16, 16);
+        stats.frames = vec![frame(1, 500, 4000.0), frame(0, 1000, 1000.0), frame(2, 500, 40000.0)];
+        let plan = stats.plan(8000.0, 0.6);
+        assert!((plan.iter().sum::<f64>() - 8000.0).abs() < 1e-6);
+        // same size, more motion: fewer bits
+        assert!(plan[1] > plan[2]);
+
+        // the same qp for every frame: bits follow the first pass sizes
+        let plan = stats.plan(8000.0, 1.0);
+        assert!((plan[0] - 4000.0).abs() < 1e-6);
+        assert!((plan[1] - 2000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn small_sads_count_as_one_per_pixel() {
+        let mut stats = PassStats::new(16, 16);
+        stats.frames = vec![frame(0, 1000, 0.0), frame(1, 1000, 256.0)];
+        let plan = stats.plan(2000.0, 0.6);
+        assert!((plan[0] - plan[1]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stats_round_trip() {
+        let mut stats = PassStats::new(1920, 1080);
+        stats.frames.push(PassFrame {
+            frame_type: FrameType::IFrame,
+            ..frame(0, 52000, 812345.0)
+        });
+        stats.frames.push(frame(3, 9000, 120000.0));
+        stats.frames.push(PassFrame {
+            frame_type: FrameType::BFrame,
+            qp: -4,
+            ..frame(1, 1, 0.0)
+        });
+
+        let filename = std::env::temp_dir().join(format!("rvc2_pass_test_{}.log", std::process::id()));
+        stats.write(&filename).unwrap();
+        let read = PassStats::read(&filename);
+        std::fs::remove_file(&filename).unwrap();
+        let read = read.unwrap();
+
+        assert_eq!((read.width, read.height), (1920, 1080));
+        assert_eq!(read.frames.len(), stats.frames.len());
+        for (read, frame) in read.frames.iter().zip(&stats.frames) {
+            assert_eq!(
+                (read.display_index, read.frame_type, read.qp, read.size, read.motion_sad),
+                (
+                    frame.display_index,
+                    frame.frame_type,
+                    frame.qp,
+                    frame.size,
+                    frame.motion_sad
+                )
+            );
+        }
+    }
+}
@@ -1,23 +1,34 @@
-use std::{io::Write, path::Path};
+use std::{
+    io::{Read, Seek, Write},
+    path::Path,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use image::{ImageBuffer, ImageReader, Rgb};
 
 use crate::{
     bitio::{BitReader, BitWriter},
-    blocks::{Block, QMatrices},
+    blocks::{Block, QMatrices, MAX_QP, MIN_QP},
     colors::{rgb2yuv, yuv2rgb},
-    motion::{BlockType, MotionMap},
+    container::{ContainerHeader, ContainerReader},
+    intra::{self, IntraMode, INTRA_MODES},
+    motion::{BlockType, MotionMap, MotionSearch, DEFAULT_SEARCH_RANGE},
     planes::Plane,
 };
 
+/// Coding type of a frame: intra, predicted from the previous anchor,
+/// or bidirectionally predicted from the surrounding anchors.
 #[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum FrameType {
     IFrame,
     PFrame,
     BFrame,
 }
 
+/// A YUV 4:2:0 frame. Planes are padded to a multiple of 16 pixels (`width` x `height`),
+/// `source_width` x `source_height` is the visible part.
 #[derive(Clone)]
 pub struct VideoFrame {
     pub y_plane: Plane,
@@ -30,16 +41,93 @@ pub struct VideoFrame {
     pub height: u32,
 }
 
+/// A 16x16 macroblock: four luma blocks followed by one U and one V block.
+#[derive(Clone)]
 pub struct MacroBlock(pub [Block; 4 + 1 + 1]);
 
+/// Encodes single frames into the container frame format.
 pub struct Encoder {
     buffer_dct: Vec<u8>,
-    buffer_mprev: Vec<u8>,
-    buffer_mnext: Vec<u8>,
     data: [u8; 1],
+    qp: i32,
+    motion_sad: f64,
+    aq_strength: f64,
+    mb_qp: Vec<i32>,
+    search_range: u32,
+    search: MotionSearch,
+    // B-frames are not references, but intra-coded macroblocks predict from their decoded neighbours
+    b_reconstructed: VideoFrame,
+}
+
+// frame header flags
+const FLAG_MB_QP: u8 = 1; // every coded macroblock has a qp delta
+const FLAGS_KNOWN: u8 = FLAG_MB_QP;
+
+// limit of adaptive quantization offsets
+const MAX_AQ_OFFSET: i32 = 12;
+
+// Matrices for the qp of the current macroblock, rescaled only when the qp changes.
+struct QpMatrices<'a> {
+    base: &'a QMatrices,
+    qp: i32,
+    matrices: QMatrices,
+}
+
+impl<'a> QpMatrices<'a> {
+    fn new(base: &'a QMatrices, qp: i32) -> QpMatrices<'a> {
+        return QpMatrices {
+            base,
+            qp,
+            matrices: base.scaled(qp),
+        };
+    }
+
+    fn get(&mut self, qp: i32) -> &QMatrices {
+        if qp != self.qp {
+            self.qp = qp;
+            self.matrices = self.base.scaled(qp);
+        }
+        return &self.matrices;
+    }
+}
+
+/// Decodes single frames written by [`Encoder`].
+pub struct FrameDecoder {
+    mblock: MacroBlock,
+    prev_block: MacroBlock,
+    next_block: MacroBlock,
+    mprev: MotionMap,
+    mnext: MotionMap,
+}
+
+/// Reads frames from a container and returns them in display order.
+pub struct Decoder<R: Read + Seek> {
+    container: ContainerReader<R>,
+    coder: FrameDecoder,
+    frame: VideoFrame,
+    prev_frame: VideoFrame,
+    next_frame: VideoFrame,
+    data: Vec<u8>,
+    // display index of next_frame while it waits for the following anchor
+    pending_anchor: Option<u32>,
+    first_index: u32,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(FrameType::IFrame),
+            1 => Ok(FrameType::PFrame),
+            2 => Ok(FrameType::BFrame),
+            _ => bail!("Unknown frame type {}", value),
+        }
+    }
 }
 
 impl VideoFrame {
+    /// Creates a black frame for a picture of `width` x `height` pixels.
     pub fn new(width: u32, height: u32) -> VideoFrame {
         let plane_width = (width as f64 / 16.0).ceil() as u32 * 16;
         let plane_height = (height as f64 / 16.0).ceil() as u32 * 16;
@@ -54,6 +142,7 @@ impl VideoFrame {
         };
     }
 
+    /// Loads an image file, converting it to YUV and padding it to the frame size.
     pub fn load_from_image<P: AsRef<Path>>(&mut self, filename: P) -> Result<()> {
         let img = ImageReader::open(filename)?.decode()?.to_rgb8();
 
@@ -82,6 +171,7 @@ impl VideoFrame {
         return Ok(());
     }
 
+    /// Saves the visible part of the frame as an RGB image, the format is chosen by extension.
     pub fn save_to_image<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
         let mut image = ImageBuffer::new(self.source_width, self.source_height);
         for (px, py, pixel) in image.enumerate_pixels_mut() {
@@ -104,6 +194,17 @@ impl VideoFrame {
         self.v_plane.extract_block(x / 2, y / 2, &mut block.0[5]);
     }
 
+    /// Extracts the macroblock at (`x`, `y`) in quarter pixels. Chroma planes have half the
+    /// resolution, so the same coordinates are eighth pixels there and need no rounding.
+    pub fn extract_macroblock_subpel(&self, x: i32, y: i32, block: &mut MacroBlock) {
+        self.y_plane.extract_block_quarter(x, y, &mut block.0[0]);
+        self.y_plane.extract_block_quarter(x + 32, y, &mut block.0[1]);
+        self.y_plane.extract_block_quarter(x, y + 32, &mut block.0[2]);
+        self.y_plane.extract_block_quarter(x + 32, y + 32, &mut block.0[3]);
+        self.u_plane.extract_block_eighth(x, y, &mut block.0[4]);
+        self.v_plane.extract_block_eighth(x, y, &mut block.0[5]);
+    }
+
     pub fn apply_macroblock(&mut self, x: u32, y: u32, block: &MacroBlock) {
         self.y_plane.apply_block(x, y, &block.0[0]);
         self.y_plane.apply_block(x + 8, y, &block.0[1]);
@@ -114,11 +215,62 @@ impl VideoFrame {
     }
 }
 
+// component of each block in a macroblock: Y, U or V
+const BLOCK_COMPONENTS: [usize; 6] = [0, 0, 0, 0, 1, 2];
+
+/// DC of the last block of each component (Y, U, V) in a frame. I-frame blocks code their DC
+/// as the difference from it, as JPEG does. Motion-compensated residuals are centred on 0
+/// already and gain nothing from it.
+#[derive(Clone, Copy, Debug)]
+pub struct DcPredictor {
+    dc: [i16; 3],
+    enabled: bool,
+}
+
+impl Default for DcPredictor {
+    fn default() -> DcPredictor {
+        return DcPredictor::new();
+    }
+}
+
+impl DcPredictor {
+    /// Predictor for the first macroblock of an intra-coded frame.
+    pub fn new() -> DcPredictor {
+        return DcPredictor {
+            dc: [0; 3],
+            enabled: true,
+        };
+    }
+
+    /// Predicts 0 for every block.
+    pub fn disabled() -> DcPredictor {
+        return DcPredictor {
+            dc: [0; 3],
+            enabled: false,
+        };
+    }
+
+    // predicted DC for block `index` of a macroblock, updated to the block's DC by the block coder
+    fn slot(&mut self, index: usize) -> &mut i16 {
+        if !self.enabled {
+            self.dc = [0; 3];
+        }
+        return &mut self.dc[BLOCK_COMPONENTS[index]];
+    }
+}
+
 impl MacroBlock {
     pub fn new() -> MacroBlock {
         return MacroBlock(core::array::from_fn(|_| Block::new()));
     }
 
+    /// Centres pixels on 0 before the DCT.
+    pub fn normalize(&mut self) {
+        for block in self.0.iter_mut() {
+            block.normalize();
+        }
+    }
+
     pub fn difference(&mut self, other: &MacroBlock) {
         for (block, other_block) in self.0.iter_mut().zip(other.0.iter()) {
             for (d, other_d) in block.0.iter_mut().zip(other_block.0.iter()) {
@@ -152,23 +304,23 @@ impl MacroBlock {
         self.0[5].encode3(&qmatrices.chroma);
     }
 
-    pub fn write(&self, writer: &mut BitWriter) -> Result<()> {
-        self.0[0].write(writer, true)?;
-        self.0[1].write(writer, true)?;
-        self.0[2].write(writer, true)?;
-        self.0[3].write(writer, true)?;
-        self.0[4].write(writer, false)?;
-        self.0[5].write(writer, false)?;
+    pub fn write(&self, writer: &mut BitWriter, dc_pred: &mut DcPredictor) -> Result<()> {
+        self.0[0].write(writer, true, dc_pred.slot(0))?;
+        self.0[1].write(writer, true, dc_pred.slot(1))?;
+        self.0[2].write(writer, true, dc_pred.slot(2))?;
+        self.0[3].write(writer, true, dc_pred.slot(3))?;
+        self.0[4].write(writer, false, dc_pred.slot(4))?;
+        self.0[5].write(writer, false, dc_pred.slot(5))?;
         return Ok(());
     }
 
-    pub fn get_encoded_size(&self, qmatrices: &QMatrices) -> usize {
-        self.0[0].get_encoded_size(&qmatrices.luma, true)
-            + self.0[1].get_encoded_size(&qmatrices.luma, true)
-            + self.0[2].get_encoded_size(&qmatrices.luma, true)
-            + self.0[3].get_encoded_size(&qmatrices.luma, true)
-            + self.0[4].get_encoded_size(&qmatrices.chroma, false)
-            + self.0[5].get_encoded_size(&qmatrices.chroma, false)
+    pub fn get_encoded_size(&self, qmatrices: &QMatrices, dc_pred: &mut DcPredictor) -> usize {
+        self.0[0].get_encoded_size(&qmatrices.luma, true, dc_pred.slot(0))
+            + self.0[1].get_encoded_size(&qmatrices.luma, true, dc_pred.slot(1))
+            + self.0[2].get_encoded_size(&qmatrices.luma, true, dc_pred.slot(2))
+            + self.0[3].get_encoded_size(&qmatrices.luma, true, dc_pred.slot(3))
+            + self.0[4].get_encoded_size(&qmatrices.chroma, false, dc_pred.slot(4))
+            + self.0[5].get_encoded_size(&qmatrices.chroma, false, dc_pred.slot(5))
     }
 
     pub fn decode(&mut self, qmatrices: &QMatrices) {
@@ -180,109 +332,374 @@ impl MacroBlock {
         self.0[5].decode3(&qmatrices.chroma);
     }
 
-    pub fn read(&mut self, reader: &mut BitReader) -> Result<()> {
-        self.0[0].read(reader, true)?;
-        self.0[1].read(reader, true)?;
-        self.0[2].read(reader, true)?;
-        self.0[3].read(reader, true)?;
-        self.0[4].read(reader, false)?;
-        self.0[5].read(reader, false)?;
+    /// Bit `i` is set when block `i` has a non-zero coefficient.
+    pub fn coded_block_pattern(&self) -> u8 {
+        let mut pattern = 0u8;
+        for (index, block) in self.0.iter().enumerate() {
+            if !block.is_zero() {
+                pattern |= 1 << index;
+            }
+        }
+        return pattern;
+    }
+
+    /// Writes the 6-bit coded block pattern followed by the blocks it marks.
+    pub fn write_coded(&self, writer: &mut BitWriter, dc_pred: &mut DcPredictor) -> Result<()> {
+        let pattern = self.coded_block_pattern();
+        for index in 0..6 {
+            writer.write_bit((pattern >> index) & 1)?;
+        }
+        for (index, block) in self.0.iter().enumerate() {
+            if pattern & (1 << index) != 0 {
+                block.write(writer, index < 4, dc_pred.slot(index))?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Reads a macroblock written by [`MacroBlock::write_coded`], blocks left out are all zeros.
+    pub fn read_coded(&mut self, reader: &mut BitReader, dc_pred: &mut DcPredictor) -> Result<()> {
+        let mut pattern = 0u8;
+        for index in 0..6 {
+            pattern |= reader.read_bit()? << index;
+        }
+        for (index, block) in self.0.iter_mut().enumerate() {
+            if pattern & (1 << index) != 0 {
+                block.read(reader, index < 4, dc_pred.slot(index))?;
+            } else {
+                block.0.fill(0.0);
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn read(&mut self, reader: &mut BitReader, dc_pred: &mut DcPredictor) -> Result<()> {
+        self.0[0].read(reader, true, dc_pred.slot(0))?;
+        self.0[1].read(reader, true, dc_pred.slot(1))?;
+        self.0[2].read(reader, true, dc_pred.slot(2))?;
+        self.0[3].read(reader, true, dc_pred.slot(3))?;
+        self.0[4].read(reader, false, dc_pred.slot(4))?;
+        self.0[5].read(reader, false, dc_pred.slot(5))?;
         return Ok(());
     }
 }
 
+/// Builds the motion-compensated prediction of the macroblock at (`x`, `y`) from the
+/// reference frames. Returns `false` if neither reference has a motion vector.
+fn predict_macroblock(
+    x: u32,
+    y: u32,
+    prev: Option<(&VideoFrame, BlockType)>,
+    next: Option<(&VideoFrame, BlockType)>,
+    prediction: &mut MacroBlock,
+    temp: &mut MacroBlock,
+) -> bool {
+    let prev = match prev {
+        Some((frame, BlockType::Motion(vx, vy))) => Some((frame, vx, vy)),
+        _ => None,
+    };
+    let next = match next {
+        Some((frame, BlockType::Motion(vx, vy))) => Some((frame, vx, vy)),
+        _ => None,
+    };
+    let x = x as i32 * 4;
+    let y = y as i32 * 4;
+    match (prev, next) {
+        (Some((prev_frame, pvx, pvy)), Some((next_frame, nvx, nvy))) => {
+            prev_frame.extract_macroblock_subpel(x + pvx, y + pvy, prediction);
+            next_frame.extract_macroblock_subpel(x + nvx, y + nvy, temp);
+            prediction.average(temp);
+        }
+        (Some((frame, vx, vy)), None) | (None, Some((frame, vx, vy))) => {
+            frame.extract_macroblock_subpel(x + vx, y + vy, prediction);
+        }
+        (None, None) => return false,
+    }
+    return true;
+}
+
+/// Builds the spatial prediction of the macroblock at (`x`, `y`) from the pixels of `frame`
+/// above and to the left of it, which must already be decoded.
+fn predict_intra_macroblock(frame: &VideoFrame, x: u32, y: u32, mode: IntraMode, prediction: &mut MacroBlock) {
+    let mut luma = [0.0; 16 * 16];
+    intra::predict(&frame.y_plane, x, y, 16, mode, &mut luma);
+    for (index, block) in prediction.0[..4].iter_mut().enumerate() {
+        let block_x = (index % 2) * 8;
+        let block_y = (index / 2) * 8;
+        for row in 0..8 {
+            let start = block_x + (block_y + row) * 16;
+            block.0[row * 8..row * 8 + 8].copy_from_slice(&luma[start..start + 8]);
+        }
+    }
+    intra::predict(&frame.u_plane, x / 2, y / 2, 8, mode, &mut prediction.0[4].0);
+    intra::predict(&frame.v_plane, x / 2, y / 2, 8, mode, &mut prediction.0[5].0);
+}
+
+// Writes a quantized P- or B-frame macroblock: a skip flag, set when every block is zero, then unless
+// skipped the qp delta (when the frame has per-macroblock qps) and the coded blocks. Returns false if skipped.
+fn write_inter_macroblock(
+    writer: &mut BitWriter,
+    mblock: &MacroBlock,
+    qp_delta: Option<i32>,
+    dc_pred: &mut DcPredictor,
+) -> Result<bool> {
+    if mblock.coded_block_pattern() == 0 {
+        writer.write_bit(1)?;
+        return Ok(false);
+    }
+    writer.write_bit(0)?;
+    if let Some(qp_delta) = qp_delta {
+        writer.write_se(qp_delta)?;
+    }
+    mblock.write_coded(writer, dc_pred)?;
+    return Ok(true);
+}
+
+// picks the intra mode whose residual codes smallest, leaves its prediction in `prediction`
+fn choose_intra_mode(
+    reconstructed: &VideoFrame,
+    x: u32,
+    y: u32,
+    source: &MacroBlock,
+    qmatrices: &QMatrices,
+    dc_pred: &DcPredictor,
+    prediction: &mut MacroBlock,
+) -> IntraMode {
+    let mut residual = MacroBlock::new();
+    let mut best_mode = IntraMode::Dc;
+    let mut best_size = usize::MAX;
+    for mode in INTRA_MODES {
+        if !mode.is_available(x, y) {
+            continue;
+        }
+        predict_intra_macroblock(reconstructed, x, y, mode, prediction);
+        residual.clone_from(source);
+        residual.difference(prediction);
+        let size = residual.get_encoded_size(qmatrices, &mut dc_pred.clone());
+        if size < best_size {
+            best_mode = mode;
+            best_size = size;
+        }
+    }
+    predict_intra_macroblock(reconstructed, x, y, best_mode, prediction);
+    return best_mode;
+}
+
 impl Encoder {
     pub fn new() -> Encoder {
         return Encoder {
             buffer_dct: Vec::<u8>::new(),
-            buffer_mprev: Vec::<u8>::new(),
-            buffer_mnext: Vec::<u8>::new(),
             data: [0u8; 1],
+            qp: 0,
+            motion_sad: 0.0,
+            aq_strength: 0.0,
+            mb_qp: Vec::new(),
+            search_range: DEFAULT_SEARCH_RANGE,
+            search: MotionSearch::Hexagon,
+            b_reconstructed: VideoFrame::new(0, 0),
         };
     }
 
-    pub fn encode_i_frame(&mut self, frame: &VideoFrame, file: &mut dyn Write, qmatrices: &QMatrices) -> Result<u64> {
+    /// Sets the strength of adaptive quantization, 0 disables it. Macroblocks get qp offsets of
+    /// `strength * 3 * log2(variance / average variance)`, so flat areas, where banding shows,
+    /// are quantized finer and busy textures coarser.
+    pub fn set_aq_strength(&mut self, strength: f64) {
+        self.aq_strength = strength.max(0.0);
+    }
+
+    /// Motion search range in pixels, see [`MotionMap::set_search_range`].
+    pub fn set_search_range(&mut self, range: u32) {
+        self.search_range = range;
+    }
+
+    /// Integer motion search, see [`MotionMap::set_search`].
+    pub fn set_search(&mut self, search: MotionSearch) {
+        self.search = search;
+    }
+
+    // fills mb_qp for `frame`, returns the frame header flags
+    fn plan_macroblock_qp(&mut self, frame: &VideoFrame) -> u8 {
+        let mv_width = (frame.width as f64 / 16.0).ceil() as u32;
+        let mv_height = (frame.height as f64 / 16.0).ceil() as u32;
+        self.mb_qp.clear();
+        if self.aq_strength <= 0.0 {
+            self.mb_qp.resize((mv_width * mv_height) as usize, self.qp);
+            return 0;
+        }
+
+        let mut activity = Vec::with_capacity((mv_width * mv_height) as usize);
+        for my in 0..mv_height {
+            for mx in 0..mv_width {
+                let variance = frame.y_plane.block_variance(mx * 16, my * 16, 16);
+                activity.push((variance + 1.0).log2());
+            }
+        }
+        let average = activity.iter().sum::<f64>() / activity.len() as f64;
+        for mb_activity in activity {
+            let offset = (self.aq_strength * 3.0 * (mb_activity - average)).round() as i32;
+            let mb_qp = self.qp + offset.clamp(-MAX_AQ_OFFSET, MAX_AQ_OFFSET);
+            self.mb_qp.push(mb_qp.clamp(MIN_QP, MAX_QP));
+        }
+        return FLAG_MB_QP;
+    }
+
+    fn write_frame_header(&mut self, file: &mut dyn Write, frame_type: FrameType, flags: u8) -> Result<()> {
+        self.data[0] = frame_type as u8;
+        file.write_all(&self.data)?;
+        file.write_i8(self.qp as i8)?;
+        file.write_u8(flags)?;
+        return Ok(());
+    }
+
+    /// Sets the quantizer parameter for the following frames, see [`QMatrices::scaled`].
+    pub fn set_qp(&mut self, qp: i32) {
+        self.qp = qp.clamp(MIN_QP, MAX_QP);
+    }
+
+    pub fn qp(&self) -> i32 {
+        return self.qp;
+    }
+
+    /// Luma SAD left after prediction in the last frame: after motion search for P- and B-frames,
+    /// the smaller of the two directions for B-frames, and after intra prediction for I-frames.
+    pub fn motion_sad(&self) -> f64 {
+        return self.motion_sad;
+    }
+
+    /// Writes an intra-coded frame, returns the number of bytes written after the size prefix.
+    /// `reconstructed` receives the frame as the decoder will see it.
+    pub fn encode_i_frame(
+        &mut self,
+        frame: &VideoFrame,
+        file: &mut dyn Write,
+        qmatrices: &QMatrices,
+        reconstructed: &mut VideoFrame,
+    ) -> Result<u64> {
+        let mut qmatrices = QpMatrices::new(qmatrices, self.qp);
+        let flags = self.plan_macroblock_qp(frame);
+        self.motion_sad = 0.0;
         let mut writer = BitWriter::new(&mut self.buffer_dct);
         let mv_width = (frame.width as f64 / 16.0).ceil() as u32;
         let mv_height = (frame.height as f64 / 16.0).ceil() as u32;
         let mut mblock = MacroBlock::new();
+        let mut prediction = MacroBlock::new();
+        let mut dc_pred = DcPredictor::new();
 
         for my in 0..mv_height {
             for mx in 0..mv_width {
+                let mb_qp = self.mb_qp[(mx + my * mv_width) as usize];
+                let qmatrices = qmatrices.get(mb_qp);
+
                 frame.extract_macroblock(mx * 16, my * 16, &mut mblock);
+                let mode = choose_intra_mode(
+                    reconstructed,
+                    mx * 16,
+                    my * 16,
+                    &mblock,
+                    qmatrices,
+                    &dc_pred,
+                    &mut prediction,
+                );
+                mode.write(&mut writer)?;
+                if flags & FLAG_MB_QP != 0 {
+                    writer.write_se(mb_qp - self.qp)?;
+                }
+                mblock.difference(&prediction);
+                self.motion_sad += mblock.0[..4]
+                    .iter()
+                    .map(|block| block.0.iter().map(|d| d.abs()).sum::<f64>())
+                    .sum::<f64>();
                 mblock.encode(qmatrices);
-                mblock.write(&mut writer)?;
+                mblock.write(&mut writer, &mut dc_pred)?;
+
+                mblock.decode(qmatrices);
+                mblock.add(&prediction);
+                reconstructed.apply_macroblock(mx * 16, my * 16, &mblock);
             }
         }
         writer.flush()?;
 
-        let dct_size = self.buffer_dct.len() as u32;
-        let frame_size = 1 + dct_size + 4; // frame_type+dct+dct_size
+        let frame_size = 1 + 1 + 1 + self.buffer_dct.len() as u32; // frame_type+qp+flags+data
 
-        file.write_all(&frame_size.to_ne_bytes())?;
-        self.data[0] = FrameType::IFrame as u8;
-        file.write_all(&self.data)?;
+        file.write_u32::<LE>(frame_size)?;
+        self.write_frame_header(file, FrameType::IFrame, flags)?;
 
-        file.write_all(&dct_size.to_ne_bytes())?;
         file.write_all(&self.buffer_dct)?;
 
         self.buffer_dct.clear();
         return Ok(frame_size as u64);
     }
 
+    /// Writes a frame predicted from `prev_frame`, returns the number of bytes written after the size prefix.
+    /// `prev_frame` should be a reconstruction, not the source frame, so that the encoder predicts
+    /// from the same pixels as the decoder. `reconstructed` receives the decoded result.
     pub fn encode_p_frame(
         &mut self,
         frame: &VideoFrame,
         prev_frame: &VideoFrame,
         file: &mut dyn Write,
         qmatrices: &QMatrices,
+        reconstructed: &mut VideoFrame,
     ) -> Result<u64> {
+        let mut qmatrices = QpMatrices::new(qmatrices, self.qp);
+        let flags = self.plan_macroblock_qp(frame);
         let mut motion = MotionMap::new(&frame);
-        motion.calculate(&frame, &prev_frame);
-        motion.write(&mut self.buffer_mprev)?;
+        motion.set_search_range(self.search_range);
+        motion.set_search(self.search);
+        self.motion_sad = motion.calculate(&frame, &prev_frame);
 
         let mut writer = BitWriter::new(&mut self.buffer_dct);
         let mv_width = (frame.width as f64 / 16.0).ceil() as u32;
         let mv_height = (frame.height as f64 / 16.0).ceil() as u32;
         let mut mblock1 = MacroBlock::new();
         let mut mblock2 = MacroBlock::new();
+        let mut mblock3 = MacroBlock::new();
+        let mut dc_pred = DcPredictor::disabled();
 
         for my in 0..mv_height {
             for mx in 0..mv_width {
                 let dst_x = mx * 16;
                 let dst_y = my * 16;
                 let mv_index = (mx + my * mv_width) as usize;
+                let mb_qp = self.mb_qp[mv_index];
+                let qmatrices = qmatrices.get(mb_qp);
 
                 frame.extract_macroblock(dst_x, dst_y, &mut mblock1);
 
-                if let BlockType::Motion(vx, vy) = motion.vectors[mv_index] {
-                    prev_frame.extract_macroblock((dst_x as i32 + vx) as u32, (dst_y as i32 + vy) as u32, &mut mblock2);
-                    mblock1.difference(&mblock2);
+                motion.write_vector(&mut writer, mv_index)?;
+                let prev = Some((prev_frame, motion.vectors[mv_index]));
+                if !predict_macroblock(dst_x, dst_y, prev, None, &mut mblock2, &mut mblock3) {
+                    let mode =
+                        choose_intra_mode(reconstructed, dst_x, dst_y, &mblock1, qmatrices, &dc_pred, &mut mblock2);
+                    mode.write(&mut writer)?;
                 }
+                mblock1.difference(&mblock2);
 
                 mblock1.encode(qmatrices);
-                mblock1.write(&mut writer)?;
+                let qp_delta = (flags & FLAG_MB_QP != 0).then_some(mb_qp - self.qp);
+                if write_inter_macroblock(&mut writer, &mblock1, qp_delta, &mut dc_pred)? {
+                    mblock1.decode(qmatrices);
+                    mblock1.add(&mblock2);
+                    reconstructed.apply_macroblock(dst_x, dst_y, &mblock1);
+                } else {
+                    reconstructed.apply_macroblock(dst_x, dst_y, &mblock2);
+                }
             }
         }
         writer.flush()?;
 
-        let dct_size = self.buffer_dct.len() as u32;
-        let motion_size = self.buffer_mprev.len() as u32;
-        let frame_size = 1 + motion_size + 4 + dct_size + 4; // frame_type+mprev+mprev_size+dct+dct_size
+        let frame_size = 1 + 1 + 1 + self.buffer_dct.len() as u32; // frame_type+qp+flags+data
 
-        file.write_all(&frame_size.to_ne_bytes())?;
-        self.data[0] = FrameType::PFrame as u8;
-        file.write_all(&self.data)?;
-        file.write_all(&motion_size.to_ne_bytes())?;
-        file.write_all(&self.buffer_mprev)?;
-        file.write_all(&dct_size.to_ne_bytes())?;
+        file.write_u32::<LE>(frame_size)?;
+        self.write_frame_header(file, FrameType::PFrame, flags)?;
         file.write_all(&self.buffer_dct)?;
 
         self.buffer_dct.clear();
-        self.buffer_mprev.clear();
         return Ok(frame_size as u64);
     }
 
+    /// Writes a frame predicted from `prev_frame` and `next_frame` (reconstructions of the anchors),
+    /// returns the number of bytes written after the size prefix.
     pub fn encode_b_frame(
         &mut self,
         frame: &VideoFrame,
@@ -291,13 +708,21 @@ impl Encoder {
         file: &mut dyn Write,
         qmatrices: &QMatrices,
     ) -> Result<u64> {
+        let mut qmatrices = QpMatrices::new(qmatrices, self.qp);
+        let flags = self.plan_macroblock_qp(frame);
         let mut motion_prev = MotionMap::new(&frame);
-        motion_prev.calculate(&frame, &prev_frame);
-        motion_prev.write(&mut self.buffer_mprev)?;
+        motion_prev.set_search_range(self.search_range);
+        motion_prev.set_search(self.search);
+        let sad_prev = motion_prev.calculate(&frame, &prev_frame);
 
         let mut motion_next = MotionMap::new(&frame);
-        motion_next.calculate(&frame, &next_frame);
-        motion_next.write(&mut self.buffer_mnext)?;
+        motion_next.set_search_range(self.search_range);
+        motion_next.set_search(self.search);
+        let sad_next = motion_next.calculate(&frame, &next_frame);
+        self.motion_sad = sad_prev.min(sad_next);
+        if self.b_reconstructed.width != frame.width || self.b_reconstructed.height != frame.height {
+            self.b_reconstructed = VideoFrame::new(frame.source_width, frame.source_height);
+        }
 
         let mut writer = BitWriter::new(&mut self.buffer_dct);
         let mv_width = (frame.width as f64 / 16.0).ceil() as u32;
@@ -305,65 +730,594 @@ impl Encoder {
         let mut mblock1 = MacroBlock::new();
         let mut mblock2 = MacroBlock::new();
         let mut mblock3 = MacroBlock::new();
+        let mut dc_pred = DcPredictor::disabled();
 
         for my in 0..mv_height {
             for mx in 0..mv_width {
                 let dst_x = mx * 16;
                 let dst_y = my * 16;
                 let mv_index = (mx + my * mv_width) as usize;
+                let mb_qp = self.mb_qp[mv_index];
+                let qmatrices = qmatrices.get(mb_qp);
 
                 frame.extract_macroblock(dst_x, dst_y, &mut mblock1);
 
-                if let BlockType::Motion(pvx, pvy) = motion_prev.vectors[mv_index] {
-                    prev_frame.extract_macroblock(
-                        (dst_x as i32 + pvx) as u32,
-                        (dst_y as i32 + pvy) as u32,
+                motion_prev.write_vector(&mut writer, mv_index)?;
+                motion_next.write_vector(&mut writer, mv_index)?;
+                let prev = Some((prev_frame, motion_prev.vectors[mv_index]));
+                let next = Some((next_frame, motion_next.vectors[mv_index]));
+                if !predict_macroblock(dst_x, dst_y, prev, next, &mut mblock2, &mut mblock3) {
+                    let mode = choose_intra_mode(
+                        &self.b_reconstructed,
+                        dst_x,
+                        dst_y,
+                        &mblock1,
+                        qmatrices,
+                        &dc_pred,
                         &mut mblock2,
                     );
-
-                    if let BlockType::Motion(nvx, nvy) = motion_next.vectors[mv_index] {
-                        next_frame.extract_macroblock(
-                            (dst_x as i32 + nvx) as u32,
-                            (dst_y as i32 + nvy) as u32,
-                            &mut mblock3,
-                        );
-                        mblock2.average(&mblock3);
-                    }
-
-                    mblock1.difference(&mblock2);
-                } else if let BlockType::Motion(nvx, nvy) = motion_next.vectors[mv_index] {
-                    next_frame.extract_macroblock(
-                        (dst_x as i32 + nvx) as u32,
-                        (dst_y as i32 + nvy) as u32,
-                        &mut mblock3,
-                    );
-                    mblock1.difference(&mblock3);
+                    mode.write(&mut writer)?;
                 }
+                mblock1.difference(&mblock2);
 
                 mblock1.encode(qmatrices);
-                mblock1.write(&mut writer)?;
+                let qp_delta = (flags & FLAG_MB_QP != 0).then_some(mb_qp - self.qp);
+                if write_inter_macroblock(&mut writer, &mblock1, qp_delta, &mut dc_pred)? {
+                    mblock1.decode(qmatrices);
+                    mblock1.add(&mblock2);
+                    self.b_reconstructed.apply_macroblock(dst_x, dst_y, &mblock1);
+                } else {
+                    self.b_reconstructed.apply_macroblock(dst_x, dst_y, &mblock2);
+                }
             }
         }
         writer.flush()?;
 
-        let dct_size = self.buffer_dct.len() as u32;
-        let mprev_size = self.buffer_mprev.len() as u32;
-        let mnext_size = self.buffer_mnext.len() as u32;
-        let frame_size = 1 + mprev_size + 4 + mnext_size + 4 + dct_size + 4; // frame_type+mprev+mprev_size+mnext+mnext_size+dct+dct_size
+        let frame_size = 1 + 1 + 1 + self.buffer_dct.len() as u32; // frame_type+qp+flags+data
 
-        file.write_all(&frame_size.to_ne_bytes())?;
-        self.data[0] = FrameType::BFrame as u8;
-        file.write_all(&self.data)?;
-        file.write_all(&mprev_size.to_ne_bytes())?;
-        file.write_all(&self.buffer_mprev)?;
-        file.write_all(&mnext_size.to_ne_bytes())?;
-        file.write_all(&self.buffer_mnext)?;
-        file.write_all(&dct_size.to_ne_bytes())?;
+        file.write_u32::<LE>(frame_size)?;
+        self.write_frame_header(file, FrameType::BFrame, flags)?;
         file.write_all(&self.buffer_dct)?;
 
         self.buffer_dct.clear();
-        self.buffer_mprev.clear();
-        self.buffer_mnext.clear();
         return Ok(frame_size as u64);
     }
 }
+
+// reads the frame quantizer parameter and flags that follow the frame type
+fn read_frame_header(file: &mut dyn Read) -> Result<(i32, u8)> {
+    let qp = file.read_i8()? as i32;
+    if !(MIN_QP..=MAX_QP).contains(&qp) {
+        bail!("Bad frame quantizer {}", qp);
+    }
+    let flags = file.read_u8()?;
+    if flags & !FLAGS_KNOWN != 0 {
+        bail!("Unknown frame flags {:#04x}", flags);
+    }
+    return Ok((qp, flags));
+}
+
+impl FrameDecoder {
+    pub fn new(width: u32, height: u32) -> FrameDecoder {
+        let frame = VideoFrame::new(width, height);
+        return FrameDecoder {
+            mblock: MacroBlock::new(),
+            prev_block: MacroBlock::new(),
+            next_block: MacroBlock::new(),
+            mprev: MotionMap::new(&frame),
+            mnext: MotionMap::new(&frame),
+        };
+    }
+
+    /// Decodes an I-frame payload (after the frame type byte) into `frame`.
+    pub fn decode_i_frame(&mut self, file: &mut dyn Read, qmatrices: &QMatrices, frame: &mut VideoFrame) -> Result<()> {
+        let header = read_frame_header(file)?;
+        self.decode_macroblocks(file, None, None, qmatrices, header, frame)?;
+        return Ok(());
+    }
+
+    /// Decodes a P-frame payload (after the frame type byte) into `frame`.
+    pub fn decode_p_frame(
+        &mut self,
+        file: &mut dyn Read,
+        prev_frame: &VideoFrame,
+        qmatrices: &QMatrices,
+        frame: &mut VideoFrame,
+    ) -> Result<()> {
+        let header = read_frame_header(file)?;
+        self.decode_macroblocks(file, Some(prev_frame), None, qmatrices, header, frame)?;
+        return Ok(());
+    }
+
+    /// Decodes a B-frame payload (after the frame type byte) into `frame`.
+    pub fn decode_b_frame(
+        &mut self,
+        file: &mut dyn Read,
+        prev_frame: &VideoFrame,
+        next_frame: &VideoFrame,
+        qmatrices: &QMatrices,
+        frame: &mut VideoFrame,
+    ) -> Result<()> {
+        let header = read_frame_header(file)?;
+        self.decode_macroblocks(file, Some(prev_frame), Some(next_frame), qmatrices, header, frame)?;
+        return Ok(());
+    }
+
+    fn decode_macroblocks(
+        &mut self,
+        file: &mut dyn Read,
+        prev_frame: Option<&VideoFrame>,
+        next_frame: Option<&VideoFrame>,
+        qmatrices: &QMatrices,
+        (qp, flags): (i32, u8),
+        frame: &mut VideoFrame,
+    ) -> Result<()> {
+        let mut qmatrices = QpMatrices::new(qmatrices, qp);
+        let mut reader = BitReader::new(file);
+        let mv_width = (frame.width as f64 / 16.0).ceil() as u32;
+        let mv_height = (frame.height as f64 / 16.0).ceil() as u32;
+        let intra_frame = prev_frame.is_none() && next_frame.is_none();
+        let mut dc_pred = if intra_frame {
+            DcPredictor::new()
+        } else {
+            DcPredictor::disabled()
+        };
+
+        for my in 0..mv_height {
+            for mx in 0..mv_width {
+                let dst_x = mx * 16;
+                let dst_y = my * 16;
+                let mv_index = (mx + my * mv_width) as usize;
+
+                let prev = match prev_frame {
+                    Some(prev_frame) => Some((prev_frame, self.mprev.read_vector(&mut reader, mv_index)?)),
+                    None => None,
+                };
+                let next = match next_frame {
+                    Some(next_frame) => Some((next_frame, self.mnext.read_vector(&mut reader, mv_index)?)),
+                    None => None,
+                };
+                if !predict_macroblock(dst_x, dst_y, prev, next, &mut self.prev_block, &mut self.next_block) {
+                    let mode = IntraMode::read(&mut reader)?;
+                    if !mode.is_available(dst_x, dst_y) {
+                        bail!("Intra mode {:?} used without neighbours", mode);
+                    }
+                    predict_intra_macroblock(frame, dst_x, dst_y, mode, &mut self.prev_block);
+                }
+                // skipped, the prediction is the result
+                if !intra_frame && reader.read_bit()? == 1 {
+                    frame.apply_macroblock(dst_x, dst_y, &self.prev_block);
+                    continue;
+                }
+
+                let mut mb_qp = qp;
+                if flags & FLAG_MB_QP != 0 {
+                    mb_qp += reader.read_se()?;
+                    if !(MIN_QP..=MAX_QP).contains(&mb_qp) {
+                        bail!("Bad macroblock quantizer {}", mb_qp);
+                    }
+                }
+
+                if intra_frame {
+                    self.mblock.read(&mut reader, &mut dc_pred)?;
+                } else {
+                    self.mblock.read_coded(&mut reader, &mut dc_pred)?;
+                }
+                self.mblock.decode(qmatrices.get(mb_qp));
+                self.mblock.add(&self.prev_block);
+                frame.apply_macroblock(dst_x, dst_y, &self.mblock);
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl<R: Read + Seek> Decoder<R> {
+    pub fn new(container: ContainerReader<R>) -> Decoder<R> {
+        let width = container.header.width;
+        let height = container.header.height;
+        return Decoder {
+            container,
+            coder: FrameDecoder::new(width, height),
+            frame: VideoFrame::new(width, height),
+            prev_frame: VideoFrame::new(width, height),
+            next_frame: VideoFrame::new(width, height),
+            data: Vec::new(),
+            pending_anchor: None,
+            first_index: 0,
+        };
+    }
+
+    pub fn header(&self) -> &ContainerHeader {
+        return &self.container.header;
+    }
+
+    pub fn container(&self) -> &ContainerReader<R> {
+        return &self.container;
+    }
+
+    /// Restarts decoding from the nearest preceding I-frame, so that the next
+    /// frame returned is the one with the given display index.
+    pub fn seek_to_frame(&mut self, display_index: u32) -> Result<()> {
+        self.container.seek_to_frame(display_index)?;
+        self.pending_anchor = None;
+        self.first_index = display_index;
+        return Ok(());
+    }
+
+    /// Decodes up to the next frame in display order and returns it with its display index.
+    pub fn next_frame(&mut self) -> Result<Option<(u32, &VideoFrame)>> {
+        loop {
+            let Some(entry) = self.container.read_frame(&mut self.data)? else {
+                return match self.pending_anchor.take() {
+                    Some(index) if index >= self.first_index => Ok(Some((index, &self.next_frame))),
+                    _ => Ok(None),
+                };
+            };
+            let mut data = &self.data[..];
+            let qmatrices = match entry.frame_type {
+                FrameType::IFrame => &self.container.i_matrices,
+                _ => &self.container.pb_matrices,
+            };
+
+            match entry.frame_type {
+                FrameType::IFrame | FrameType::PFrame => {
+                    if entry.frame_type == FrameType::IFrame {
+                        self.coder.decode_i_frame(&mut data, qmatrices, &mut self.frame)?;
+                    } else {
+                        self.coder
+                            .decode_p_frame(&mut data, &self.next_frame, qmatrices, &mut self.frame)?;
+                    }
+                    std::mem::swap(&mut self.prev_frame, &mut self.next_frame);
+                    std::mem::swap(&mut self.next_frame, &mut self.frame);
+                    match self.pending_anchor.replace(entry.display_index) {
+                        Some(index) if index >= self.first_index => return Ok(Some((index, &self.prev_frame))),
+                        _ => {}
+                    }
+                }
+                FrameType::BFrame => {
+                    if entry.display_index < self.first_index {
+                        // may reference an anchor before the keyframe we started from
+                        continue;
+                    }
+                    self.coder.decode_b_frame(
+                        &mut data,
+                        &self.prev_frame,
+                        &self.next_frame,
+                        qmatrices,
+                        &mut self.frame,
+                    )?;
+                    return Ok(Some((entry.display_index, &self.frame)));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for Decoder<R> {
+    type Item = Result<(u32, VideoFrame)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return match self.next_frame() {
+            Ok(Some((index, frame))) => Some(Ok((index, frame.clone()))),
+            Ok(None) => None,
+            Err(error) => Some(Err(error)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(plane: &mut Plane, scale: f64, pattern: &dyn Fn(f64, f64) -> f64) {
+        for y in 0..plane.height() {
+            for x in 0..plane.width() {
+                let value = pattern(x as f64 * scale, y as f64 * scale);
+                plane.put(x, y, value.round().clamp(0.0, 255.0));
+            }
+        }
+    }
+
+    // a frame showing `pattern` in luma pixel coordinates, chroma follows at half resolution
+    fn frame(width: u32, height: u32, pattern: &dyn Fn(f64, f64) -> f64) -> VideoFrame {
+        let mut frame = VideoFrame::new(width, height);
+        fill(&mut frame.y_plane, 1.0, pattern);
+        fill(&mut frame.u_plane, 2.0, &|x, y| 255.0 - pattern(x, y));
+        fill(&mut frame.v_plane, 2.0, &|x, y| (pattern(y, x) + 64.0) % 256.0);
+        return frame;
+    }
+
+    // smooth texture moved by (`dx`, `dy`) pixels
+    fn texture(width: u32, height: u32, dx: f64, dy: f64) -> VideoFrame {
+        return frame(width, height, &|x, y| {
+            let (x, y) = (x - dx, y - dy);
+            128.0 + 60.0 * (x / 5.0).sin() * (y / 7.0).cos() + 40.0 * ((x + y) / 11.0).sin()
+        });
+    }
+
+    fn assert_same(decoded: &VideoFrame, reconstructed: &VideoFrame) {
+        assert!(decoded.y_plane.data == reconstructed.y_plane.data, "luma differs");
+        assert!(decoded.u_plane.data == reconstructed.u_plane.data, "U differs");
+        assert!(decoded.v_plane.data == reconstructed.v_plane.data, "V differs");
+    }
+
+    // payload after the size prefix and the frame type
+    fn payload(data: &[u8], frame_type: FrameType) -> &[u8] {
+        assert_eq!(
+            data.len(),
+            u32::from_le_bytes(data[..4].try_into().unwrap()) as usize + 4
+        );
+        assert_eq!(data[4], frame_type as u8);
+        return &data[5..];
+    }
+
+    // Codes the first frame as an I-frame, the last one as a P-frame and the ones between as
+    // B-frames, decodes every frame and checks it against the encoder's reconstruction.
+    // Returns the coded sizes in coding order.
+    fn round_trip(coder: &mut Encoder, frames: &[VideoFrame]) -> Vec<usize> {
+        let qmatrices = QMatrices::new(0.9);
+        let (width, height) = (frames[0].source_width, frames[0].source_height);
+        let mut decoder = FrameDecoder::new(width, height);
+        let mut decoded = VideoFrame::new(width, height);
+        let mut prev = VideoFrame::new(width, height);
+        let mut sizes = Vec::new();
+
+        let mut data = Vec::new();
+        coder
+            .encode_i_frame(&frames[0], &mut data, &qmatrices, &mut prev)
+            .unwrap();
+        let mut reader = payload(&data, FrameType::IFrame);
+        decoder.decode_i_frame(&mut reader, &qmatrices, &mut decoded).unwrap();
+        assert_same(&decoded, &prev);
+        sizes.push(data.len());
+        if frames.len() == 1 {
+            return sizes;
+        }
+
+        let mut next = VideoFrame::new(width, height);
+        let mut data = Vec::new();
+        let last = &frames[frames.len() - 1];
+        coder
+            .encode_p_frame(last, &prev, &mut data, &qmatrices, &mut next)
+            .unwrap();
+        let mut reader = payload(&data, FrameType::PFrame);
+        decoder
+            .decode_p_frame(&mut reader, &prev, &qmatrices, &mut decoded)
+            .unwrap();
+        assert_same(&decoded, &next);
+        sizes.push(data.len());
+
+        for frame in &frames[1..frames.len() - 1] {
+            let mut data = Vec::new();
+            coder
+                .encode_b_frame(frame, &prev, &next, &mut data, &qmatrices)
+                .unwrap();
+            let mut reader = payload(&data, FrameType::BFrame);
+            decoder
+                .decode_b_frame(&mut reader, &prev, &next, &qmatrices, &mut decoded)
+                .unwrap();
+            assert_same(&decoded, &coder.b_reconstructed);
+            sizes.push(data.len());
+        }
+        return sizes;
+    }
+
+    #[test]
+    fn dc_prediction_round_trip() {
+        // blocks of very different brightness, the DC differences span the whole range
+        let frame = frame(72, 40, &|x, y| {
+            if ((x / 8.0).floor() as i32 + (y / 8.0).floor() as i32) % 2 == 0 {
+                255.0
+            } else {
+                (x * 3.0 + y) % 40.0
+            }
+        });
+        for qp in [-12, 0, 12] {
+            let mut coder = Encoder::new();
+            coder.set_qp(qp);
+            round_trip(&mut coder, std::slice::from_ref(&frame));
+        }
+    }
+
+    #[test]
+    fn intra_modes_round_trip() {
+        let qmatrices = QMatrices::new(0.9);
+        // a pattern and the mode that fits it
+        type ModePattern<'a> = (IntraMode, &'a dyn Fn(f64, f64) -> f64);
+        let patterns: [ModePattern; 3] = [
+            (IntraMode::Horizontal, &|_, y| (y * 37.0) % 256.0),
+            (IntraMode::Vertical, &|x, _| (x * 37.0) % 256.0),
+            (IntraMode::Planar, &|x, y| x * 2.0 + y),
+        ];
+        for (mode, pattern) in patterns {
+            let frame = frame(64, 48, pattern);
+            let mut coder = Encoder::new();
+            round_trip(&mut coder, std::slice::from_ref(&frame));
+
+            // the mode that fits the pattern wins inside the frame
+            let mut reconstructed = VideoFrame::new(64, 48);
+            coder
+                .encode_i_frame(&frame, &mut Vec::new(), &qmatrices, &mut reconstructed)
+                .unwrap();
+            let mut source = MacroBlock::new();
+            frame.extract_macroblock(16, 16, &mut source);
+            let chosen = choose_intra_mode(
+                &reconstructed,
+                16,
+                16,
+                &source,
+                &qmatrices,
+                &DcPredictor::new(),
+                &mut MacroBlock::new(),
+            );
+            assert_eq!(chosen, mode);
+        }
+    }
+
+    #[test]
+    fn intra_blocks_in_inter_frames_round_trip() {
+        // the second half of the sequence shows something else, its blocks are intra coded
+        let stripes = frame(64, 48, &|x, y| ((x / 3.0).floor() * 50.0 + y * 2.0) % 256.0);
+        let frames = [texture(64, 48, 0.0, 0.0), stripes.clone(), stripes];
+        let mut motion = MotionMap::new(&frames[0]);
+        motion.calculate(&frames[2], &frames[0]);
+        assert!(motion.vectors.contains(&BlockType::New));
+        round_trip(&mut Encoder::new(), &frames);
+    }
+
+    #[test]
+    fn skipped_macroblocks_round_trip() {
+        let still = texture(64, 48, 0.0, 0.0);
+        let sizes = round_trip(&mut Encoder::new(), &[still.clone(), still.clone(), still.clone()]);
+        // headers, one vector, one repeat run and a skip bit per macroblock
+        assert!(sizes[1] < 16 && sizes[2] < 20, "{:?}", sizes);
+
+        // a single changed macroblock: the others are skipped, its empty blocks left out of the pattern
+        let mut changed = still.clone();
+        for y in 16..24 {
+            for x in 24..32 {
+                changed.y_plane.put(x, y, 255.0 - still.y_plane.get(x, y));
+            }
+        }
+        let sizes = round_trip(&mut Encoder::new(), &[still.clone(), changed.clone(), changed]);
+        assert!(sizes[1] < sizes[0] / 4, "{:?}", sizes);
+    }
+
+    #[test]
+    fn macroblock_qp_round_trip() {
+        // flat on the left, busy on the right: adaptive quantization gives the macroblocks different qps
+        let busy = |dx: f64| {
+            move |x: f64, y: f64| {
+                if x < 32.0 {
+                    100.0 + y / 4.0
+                } else {
+                    128.0 + 100.0 * ((x - dx) * 1.3).sin() * (y * 0.9).cos()
+                }
+            }
+        };
+        let frames = [
+            frame(64, 48, &busy(0.0)),
+            frame(64, 48, &busy(1.0)),
+            frame(64, 48, &busy(2.0)),
+        ];
+        let mut coder = Encoder::new();
+        coder.set_qp(6);
+        coder.set_aq_strength(1.0);
+        round_trip(&mut coder, &frames);
+        assert!(coder.mb_qp.iter().any(|qp| *qp < 6) && coder.mb_qp.iter().any(|qp| *qp > 6));
+    }
+
+    #[test]
+    fn subpel_motion_round_trip() {
+        let frames = [
+            texture(64, 48, 0.0, 0.0),
+            texture(64, 48, 0.25, 0.5),
+            texture(64, 48, -0.75, 1.25),
+        ];
+        let mut motion = MotionMap::new(&frames[0]);
+        motion.calculate(&frames[2], &frames[0]);
+        let fractional = |vector: &BlockType| matches!(vector, BlockType::Motion(x, y) if x % 4 != 0 && y % 4 != 0);
+        assert!(motion.vectors.iter().any(fractional));
+        round_trip(&mut Encoder::new(), &frames);
+    }
+
+    #[test]
+    fn long_vectors_round_trip() {
+        let frames = [
+            texture(96, 64, 0.0, 0.0),
+            texture(96, 64, 12.0, -6.0),
+            texture(96, 64, 24.0, -12.0),
+        ];
+        let mut coder = Encoder::new();
+        coder.set_search_range(32);
+        coder.set_search(MotionSearch::Full);
+        round_trip(&mut coder, &frames);
+
+        let mut motion = MotionMap::new(&frames[0]);
+        motion.set_search_range(32);
+        motion.set_search(MotionSearch::Full);
+        motion.calculate(&frames[2], &frames[0]);
+        assert!(motion.vectors.contains(&BlockType::Motion(-96, 48)));
+    }
+
+    #[test]
+    fn vectors_outside_the_reference_fail_to_decode() {
+        let mut data = vec![0u8, 0u8]; // qp, flags
+        let mut writer = BitWriter::new(&mut data);
+        writer.write_ue(0).unwrap();
+        writer.write_se(256).unwrap();
+        writer.write_se(0).unwrap();
+        writer.write_bit(1).unwrap();
+        writer.flush().unwrap();
+
+        let reference = texture(32, 32, 0.0, 0.0);
+        let mut decoded = VideoFrame::new(32, 32);
+        let mut decoder = FrameDecoder::new(32, 32);
+        let result = decoder.decode_p_frame(&mut &data[..], &reference, &QMatrices::new(0.9), &mut decoded);
+        assert!(result.unwrap_err().to_string().contains("outside the reference"));
+    }
+
+    #[test]
+    fn search_methods_round_trip() {
+        let frames = [
+            texture(64, 48, 0.0, 0.0),
+            texture(64, 48, 2.5, 1.0),
+            texture(64, 48, 5.0, 2.0),
+        ];
+        for search in [
+            MotionSearch::Full,
+            MotionSearch::Diamond,
+            MotionSearch::Hexagon,
+            MotionSearch::ThreeStep,
+            MotionSearch::Hierarchical,
+        ] {
+            let mut coder = Encoder::new();
+            coder.set_search(search);
+            round_trip(&mut coder, &frames);
+
+            // every method finds the pan
+            let mut motion = MotionMap::new(&frames[0]);
+            motion.set_search(search);
+            motion.calculate(&frames[2], &frames[0]);
+            assert!(motion.vectors.contains(&BlockType::Motion(-20, -8)), "{}", search);
+        }
+    }
+
+    #[test]
+    fn predicted_vectors_round_trip() {
+        // the top half pans right, the bottom half down, so neighbouring vectors differ
+        let split = |shift: f64| {
+            move |x: f64, y: f64| {
+                let (x, y) = if y < 32.0 { (x - shift, y) } else { (x, y - shift) };
+                128.0 + 60.0 * (x / 5.0).sin() * (y / 7.0).cos() + 40.0 * ((x + y) / 11.0).sin()
+            }
+        };
+        let frames = [
+            frame(80, 64, &split(0.0)),
+            frame(80, 64, &split(1.5)),
+            frame(80, 64, &split(3.0)),
+        ];
+        round_trip(&mut Encoder::new(), &frames);
+    }
+
+    #[test]
+    fn long_repeat_runs_round_trip() {
+        // 80 macroblocks of the same motion, more than a repeat run used to cover
+        let frames = [
+            texture(160, 128, 0.0, 0.0),
+            texture(160, 128, 0.0, 2.0),
+            texture(160, 128, 0.0, 4.0),
+        ];
+        let mut motion = MotionMap::new(&frames[0]);
+        motion.calculate(&frames[2], &frames[0]);
+        let longest_run = motion
+            .vectors
+            .chunk_by(|a, b| a == b)
+            .map(|run| run.len())
+            .max()
+            .unwrap();
+        assert!(longest_run > 32);
+        round_trip(&mut Encoder::new(), &frames);
+    }
+}
@@ -0,0 +1,262 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    frameio::{load_plane, load_plane_subsampled, store_plane, FrameSink, FrameSource},
+    videocode::VideoFrame,
+};
+
+const SIGNATURE: &str = "YUV4MPEG2";
+const FRAME_TAG: &str = "FRAME";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Y4mChroma {
+    C420,
+    C444,
+    Mono,
+}
+
+/// Reads YUV4MPEG2 streams. 4:2:0 planes are used as is, 4:4:4 chroma is averaged down.
+pub struct Y4mReader<R: BufRead> {
+    reader: R,
+    width: u32,
+    height: u32,
+    fps: Option<f32>,
+    chroma: Y4mChroma,
+    data: Vec<u8>,
+    line: Vec<u8>,
+}
+
+/// Writes YUV4MPEG2 4:2:0 streams.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl<R: BufRead> Y4mReader<R> {
+    pub fn new(mut reader: R) -> Result<Y4mReader<R>> {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        let header = String::from_utf8_lossy(&line).trim_end().to_string();
+
+        let mut params = header.split(' ');
+        if params.next() != Some(SIGNATURE) {
+            bail!("Not a YUV4MPEG2 stream");
+        }
+
+        let mut width = 0u32;
+        let mut height = 0u32;
+        let mut fps = None;
+        let mut chroma = Y4mChroma::C420;
+        for param in params.filter(|param| !param.is_empty()) {
+            let tag = param.chars().next().unwrap();
+            let value = &param[tag.len_utf8()..];
+            match tag {
+                'W' => width = value.parse().context("Bad Y4M width")?,
+                'H' => height = value.parse().context("Bad Y4M height")?,
+                'F' => {
+                    if let Some((num, den)) = value.split_once(':') {
+                        let num: f32 = num.parse().context("Bad Y4M frame rate")?;
+                        let den: f32 = den.parse().context("Bad Y4M frame rate")?;
+                        if num > 0.0 && den > 0.0 {
+                            fps = Some(num / den);
+                        }
+                    }
+                }
+                'C' => {
+                    chroma = match value {
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Y4mChroma::C420,
+                        "444" => Y4mChroma::C444,
+                        "mono" => Y4mChroma::Mono,
+                        _ => bail!("Unsupported Y4M colour space {}", value),
+                    }
+                }
+                'I' if value != "p" && value != "?" => bail!("Interlaced Y4M input is not supported"),
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            bail!("Y4M header has no frame size");
+        }
+
+        return Ok(Y4mReader {
+            reader,
+            width,
+            height,
+            fps,
+            chroma,
+            data: Vec::new(),
+            line,
+        });
+    }
+
+    fn chroma_size(&self) -> (u32, u32) {
+        return match self.chroma {
+            Y4mChroma::C420 => (self.width.div_ceil(2), self.height.div_ceil(2)),
+            Y4mChroma::C444 => (self.width, self.height),
+            Y4mChroma::Mono => (0, 0),
+        };
+    }
+}
+
+impl<R: BufRead> FrameSource for Y4mReader<R> {
+    fn width(&self) -> u32 {
+        return self.width;
+    }
+
+    fn height(&self) -> u32 {
+        return self.height;
+    }
+
+    fn fps(&self) -> Option<f32> {
+        return self.fps;
+    }
+
+    fn frame_count(&self) -> Option<usize> {
+        return None;
+    }
+
+    fn read_frame(&mut self, frame: &mut VideoFrame) -> Result<bool> {
+        self.line.clear();
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        if !self.line.starts_with(FRAME_TAG.as_bytes()) {
+            bail!("Bad Y4M frame header");
+        }
+
+        let luma_size = (self.width * self.height) as usize;
+        let (chroma_width, chroma_height) = self.chroma_size();
+        let chroma_size = (chroma_width * chroma_height) as usize;
+        self.data.resize(luma_size + chroma_size * 2, 0);
+        self.reader.read_exact(&mut self.data)?;
+
+        let (y_data, uv_data) = self.data.split_at(luma_size);
+        let (u_data, v_data) = uv_data.split_at(chroma_size);
+        load_plane(&mut frame.y_plane, y_data, self.width, self.height, self.width);
+        match self.chroma {
+            Y4mChroma::C420 => {
+                load_plane(&mut frame.u_plane, u_data, chroma_width, chroma_height, chroma_width);
+                load_plane(&mut frame.v_plane, v_data, chroma_width, chroma_height, chroma_width);
+            }
+            Y4mChroma::C444 => {
+                load_plane_subsampled(&mut frame.u_plane, u_data, chroma_width, chroma_height, chroma_width);
+                load_plane_subsampled(&mut frame.v_plane, v_data, chroma_width, chroma_height, chroma_width);
+            }
+            Y4mChroma::Mono => {
+                frame.u_plane.fill(128.0);
+                frame.v_plane.fill(128.0);
+            }
+        }
+        return Ok(true);
+    }
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut writer: W, width: u32, height: u32, fps: f32) -> Result<Y4mWriter<W>> {
+        // frame rate as a fraction with up to three decimals, e.g. 29.97 -> 2997:100
+        let mut den = 1000u32;
+        let mut num = (fps as f64 * den as f64).round() as u32;
+        while den > 1 && num.is_multiple_of(10) {
+            num /= 10;
+            den /= 10;
+        }
+        writeln!(
+            writer,
+            "{} W{} H{} F{}:{} Ip A1:1 C420jpeg",
+            SIGNATURE, width, height, num, den
+        )?;
+        return Ok(Y4mWriter {
+            writer,
+            width,
+            height,
+            data: Vec::new(),
+        });
+    }
+}
+
+impl<W: Write> FrameSink for Y4mWriter<W> {
+    fn write_frame(&mut self, _index: u32, frame: &VideoFrame) -> Result<()> {
+        let chroma_width = self.width.div_ceil(2);
+        let chroma_height = self.height.div_ceil(2);
+        self.data.clear();
+        store_plane(&frame.y_plane, &mut self.data, self.width, self.height);
+        store_plane(&frame.u_plane, &mut self.data, chroma_width, chroma_height);
+        store_plane(&frame.v_plane, &mut self.data, chroma_width, chroma_height);
+
+        writeln!(self.writer, "{}", FRAME_TAG)?;
+        self.writer.write_all(&self.data)?;
+        self.writer.flush()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn frame(width: u32, height: u32, seed: u32) -> VideoFrame {
+        let mut frame = VideoFrame::new(width, height);
+        for (index, plane) in [&mut frame.y_plane, &mut frame.u_plane, &mut frame.v_plane]
+            .into_iter()
+            .enumerate()
+        {
+            for y in 0..plane.height() {
+                for x in 0..plane.width() {
+                    plane.put(x, y, ((x * 7 + y * 13 + seed * 31 + index as u32 * 50) % 256) as f64);
+                }
+            }
+        }
+        return frame;
+    }
+
+    fn assert_visible_same(a: &VideoFrame, b: &VideoFrame, width: u32, height: u32) {
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        for (a, b, width, height) in [
+            (&a.y_plane, &b.y_plane, width, height),
+            (&a.u_plane, &b.u_plane, chroma_width, chroma_height),
+            (&a.v_plane, &b.v_plane, chroma_width, chroma_height),
+        ] {
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(a.get(x, y), b.get(x, y), "pixel {},{} differs", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let (width, height) = (21, 17);
+        let frames: Vec<VideoFrame> = (0..3).map(|seed| frame(width, height, seed)).collect();
+
+        let mut data = Vec::new();
+        let mut writer = Y4mWriter::new(&mut data, width, height, 29.97).unwrap();
+        for (index, frame) in frames.iter().enumerate() {
+            writer.write_frame(index as u32, frame).unwrap();
+        }
+
+        let mut reader = Y4mReader::new(Cursor::new(data)).unwrap();
+        assert_eq!((reader.width(), reader.height()), (width, height));
+        assert!((reader.fps().unwrap() - 29.97).abs() < 1e-4);
+        let mut decoded = VideoFrame::new(width, height);
+        for frame in &frames {
+            assert!(reader.read_frame(&mut decoded).unwrap());
+            assert_visible_same(frame, &decoded, width, height);
+        }
+        assert!(!reader.read_frame(&mut decoded).unwrap());
+    }
+
+    #[test]
+    fn non_ascii_parameters_are_ignored() {
+        let data = "YUV4MPEG2 W4 H2 \u{e9}t\u{e9} F25:1\nFRAME\n".as_bytes().to_vec();
+        let reader = Y4mReader::new(Cursor::new(data)).unwrap();
+        assert_eq!((reader.width(), reader.height()), (4, 2));
+        assert_eq!(reader.fps(), Some(25.0));
+    }
+}